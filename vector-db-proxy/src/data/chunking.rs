@@ -1,4 +1,4 @@
-use crate::data::{models::Document, text_splitting::Chunker};
+use crate::data::{models::{Document, MessageFormat}, text_splitting::Chunker};
 use crate::mongo::models::ChunkingStrategy;
 use anyhow::{anyhow, Result};
 
@@ -31,7 +31,7 @@ pub trait Chunking {
         path: String,
         datasource_id: String,
         queue: Arc<RwLock<MyQueue<String>>>,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
+        qdrant_conn: Arc<QdrantClient>,
         mongo_conn: Arc<RwLock<Database>>,
         // redis_conn_pool: Arc<Mutex<RedisConnection>>,
     );
@@ -198,7 +198,7 @@ impl Chunking for TextChunker {
         path: String,
         datasource_id: String,
         queue: Arc<RwLock<MyQueue<String>>>,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
+        qdrant_conn: Arc<QdrantClient>,
         mongo_conn: Arc<RwLock<Database>>,
         // redis_conn_pool: Arc<Mutex<RedisConnection>>,
     ) {
@@ -212,7 +212,7 @@ impl Chunking for TextChunker {
                             let qdrant_conn = Arc::clone(&qdrant_conn);
                             let mongo_conn = Arc::clone(&mongo_conn);
                             let ds_clone = datasource_id.clone();
-                            add_message_to_embedding_queue(queue, qdrant_conn, mongo_conn, (ds_clone, string_record)).await;
+                            add_message_to_embedding_queue(queue, qdrant_conn, mongo_conn, (ds_clone, string_record), MessageFormat::Json, None, None).await;
                         }
                         Err(e) => { println!("An error occurred {}", e); }
                     }
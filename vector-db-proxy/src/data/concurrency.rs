@@ -0,0 +1,79 @@
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+/// Bounds how many messages are ingested at once, however many the consumer hands over. Callers
+/// over the limit wait for a permit rather than fail. A limit of 0 disables the gate.
+pub struct MessageGate {
+    semaphore: Option<Semaphore>,
+}
+
+impl MessageGate {
+    pub fn new(max_concurrent_messages: usize) -> Self {
+        MessageGate {
+            semaphore: (max_concurrent_messages > 0)
+                .then(|| Semaphore::new(max_concurrent_messages)),
+        }
+    }
+
+    /// Waits until fewer than `max_concurrent_messages` messages are being ingested. The slot is
+    /// held until the returned permit is dropped, None when the gate is disabled
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        // The semaphore is never closed, so acquiring can only fail if it was
+        self.semaphore.as_ref()?.acquire().await.ok()
+    }
+}
+
+static MESSAGE_GATE: OnceCell<MessageGate> = OnceCell::const_new();
+
+/// The gate shared by every ingestion path, sized by `max_concurrent_messages`
+pub async fn message_gate() -> &'static MessageGate {
+    MESSAGE_GATE
+        .get_or_init(|| async {
+            let global_data = GLOBAL_DATA.read().await;
+            MessageGate::new(global_data.max_concurrent_messages)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Runs `messages` concurrent ingestions through the gate, returning the most that were in
+    /// flight at once
+    async fn peak_concurrency(gate: MessageGate, messages: usize) -> usize {
+        let gate = Arc::new(gate);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..messages)
+            .map(|_| {
+                let (gate, in_flight, peak) = (gate.clone(), in_flight.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let _permit = gate.acquire().await;
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn at_most_the_limit_of_messages_are_ingested_at_once() {
+        assert_eq!(peak_concurrency(MessageGate::new(2), 10).await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_limit_of_zero_lets_every_message_through() {
+        assert_eq!(peak_concurrency(MessageGate::new(0), 10).await, 10);
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+use thiserror::Error as ThisError;
+
+use crate::data::models::RecordSnapshot;
+
+#[derive(Debug, ThisError)]
+pub enum ProcessError {
+    #[error("message could not be parsed as JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("record {0} of the message is not a JSON object")]
+    InvalidRecord(usize),
+    #[error("message could not be parsed as CSV: {0}")]
+    InvalidCsv(#[from] csv::Error),
+    #[error("an error occurred while looking up the embedding model: {0}")]
+    ModelLookupFailed(anyhow::Error),
+    #[error("an error occurred while looking up the datasource's tenant: {0}")]
+    TenantLookupFailed(anyhow::Error),
+    #[error("no embedding model found for datasource: {0}")]
+    ModelNotFound(String),
+    #[error("records do not match the datasource schema: {}", .0.join(", "))]
+    SchemaViolation(Vec<String>),
+    #[error("no embedding field set for datasource: {0}")]
+    NoEmbeddingField(String),
+    #[error("an error occurred while embedding records: {0}")]
+    EmbeddingFailed(anyhow::Error),
+    #[error("embedding request timed out after {0:?}")]
+    EmbeddingTimeout(Duration),
+    #[error("the embedding backend is unavailable after repeated failures, try again later")]
+    EmbeddingUnavailable,
+    #[error("embedding dimension {got} does not match the collection's vector size {expected}")]
+    DimensionMismatch { expected: u64, got: u64 },
+    #[error("hybrid search was requested but collection {0} has no sparse vectors")]
+    HybridSearchUnsupported(String),
+    #[error("collection {0} was created for hybrid search and can only be searched with it")]
+    HybridSearchRequired(String),
+    #[error("{record} failed: {source}")]
+    RecordFailed {
+        record: RecordSnapshot,
+        source: Box<ProcessError>,
+    },
+    #[error("an error occurred while upserting points to the vector store: {0}")]
+    UpsertFailed(anyhow::Error),
+}
+
+impl ProcessError {
+    /// ProcessError isn't Clone as the errors it wraps aren't, this copies it so that the same
+    /// error can be reported for several messages. Wrapped errors are copied by their message
+    pub fn duplicate(&self) -> ProcessError {
+        match self {
+            ProcessError::InvalidJson(e) => {
+                ProcessError::InvalidJson(serde::de::Error::custom(e.to_string()))
+            }
+            ProcessError::InvalidRecord(index) => ProcessError::InvalidRecord(*index),
+            ProcessError::InvalidCsv(e) => ProcessError::InvalidCsv(csv::Error::from(
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+            )),
+            ProcessError::ModelLookupFailed(e) => {
+                ProcessError::ModelLookupFailed(anyhow::anyhow!("{:#}", e))
+            }
+            ProcessError::TenantLookupFailed(e) => {
+                ProcessError::TenantLookupFailed(anyhow::anyhow!("{:#}", e))
+            }
+            ProcessError::ModelNotFound(datasource_id) => {
+                ProcessError::ModelNotFound(datasource_id.clone())
+            }
+            ProcessError::SchemaViolation(violations) => {
+                ProcessError::SchemaViolation(violations.clone())
+            }
+            ProcessError::NoEmbeddingField(datasource_id) => {
+                ProcessError::NoEmbeddingField(datasource_id.clone())
+            }
+            ProcessError::EmbeddingFailed(e) => {
+                ProcessError::EmbeddingFailed(anyhow::anyhow!("{:#}", e))
+            }
+            ProcessError::EmbeddingTimeout(timeout) => ProcessError::EmbeddingTimeout(*timeout),
+            ProcessError::EmbeddingUnavailable => ProcessError::EmbeddingUnavailable,
+            ProcessError::DimensionMismatch { expected, got } => ProcessError::DimensionMismatch {
+                expected: *expected,
+                got: *got,
+            },
+            ProcessError::HybridSearchUnsupported(collection_name) => {
+                ProcessError::HybridSearchUnsupported(collection_name.clone())
+            }
+            ProcessError::HybridSearchRequired(collection_name) => {
+                ProcessError::HybridSearchRequired(collection_name.clone())
+            }
+            ProcessError::RecordFailed { record, source } => ProcessError::RecordFailed {
+                record: record.clone(),
+                source: Box::new(source.duplicate()),
+            },
+            ProcessError::UpsertFailed(e) => ProcessError::UpsertFailed(anyhow::anyhow!("{:#}", e)),
+        }
+    }
+}
@@ -1,5 +1,11 @@
 pub mod chunking;
+pub mod concurrency;
+pub mod errors;
 pub mod models;
+pub mod preprocessing;
 pub mod processing_incoming_messages;
-mod text_splitting;
+pub mod schema_validation;
+pub mod sparse_encoding;
+pub mod streaming;
+pub mod text_splitting;
 pub mod utils;
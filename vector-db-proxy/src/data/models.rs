@@ -1,5 +1,11 @@
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use tracing::warn;
+
+use crate::data::errors::ProcessError;
+use crate::qdrant::models::HashMapValues;
 
 #[derive(Debug, Clone, Default)]
 pub struct Document {
@@ -54,3 +60,276 @@ impl From<String> for FileType {
         }
     }
 }
+
+/// Format of an incoming message body, e.g. from the message's content type
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageFormat {
+    Json,
+    Csv,
+}
+
+impl From<Option<&str>> for MessageFormat {
+    fn from(content_type: Option<&str>) -> Self {
+        match content_type.map(|c| c.to_lowercase()) {
+            Some(content_type) if content_type.contains("csv") => Self::Csv,
+            // Messages without a content type have always been JSON
+            _ => Self::Json,
+        }
+    }
+}
+
+/// What to do with an element of a message's array that isn't a JSON object
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InvalidRecordPolicy {
+    /// Drop the element, logging its index
+    Skip,
+    /// Fail the whole message
+    Fail,
+    /// Turn the element into a record: strings holding a JSON object are parsed, any other
+    /// value is wrapped as `{"value": ...}`. Nulls are skipped since they hold no data
+    Coerce,
+}
+
+impl From<String> for InvalidRecordPolicy {
+    fn from(value: String) -> Self {
+        match value.to_lowercase().as_str() {
+            "fail" => Self::Fail,
+            "coerce" => Self::Coerce,
+            _ => Self::Skip,
+        }
+    }
+}
+
+/// Records the position of a record in the message it came from in its `source_index` field, so
+/// that points can be traced back to the row that produced them. Overwrites any existing field of
+/// that name
+pub fn stamp_source_index(record: &mut Map<String, Value>, source_index: usize) {
+    record.insert("source_index".to_string(), Value::from(source_index));
+}
+
+/// Field names (lowercased) containing any of these have their value left out of snapshots
+const SENSITIVE_FIELD_MARKERS: [&str; 8] = [
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "api_key",
+    "apikey",
+    "authorization",
+    "credential",
+];
+const SNAPSHOT_VALUE_CHARS: usize = 64;
+const SNAPSHOT_PREVIEW_CHARS: usize = 256;
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Identifies the record an ingestion error came from without dumping it into logs in full: its
+/// `source_index`, a hash of its whole content and a preview in which every value is truncated
+/// and fields that look like secrets are redacted
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordSnapshot {
+    pub source_index: Option<u64>,
+    pub content_hash: String,
+    pub preview: String,
+}
+
+impl RecordSnapshot {
+    pub fn from_record(record: &HashMap<String, HashMapValues>) -> Self {
+        // Sorted so that the hash and the preview don't depend on the map's iteration order
+        let fields: BTreeMap<&String, Value> = record
+            .iter()
+            .map(|(k, v)| (k, Value::from(v.clone())))
+            .collect();
+        let source_index = record
+            .get("source_index")
+            .and_then(|index| Value::from(index.clone()).as_u64());
+        // FNV-1a, stable across builds so that hashes in logs can be compared between deployments
+        let content_hash = serde_json::to_vec(&fields)
+            .unwrap_or_default()
+            .iter()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            });
+        let preview: Vec<String> = fields
+            .iter()
+            .filter(|(k, _)| k.as_str() != "source_index")
+            .map(|(k, v)| {
+                let field = k.to_lowercase();
+                if SENSITIVE_FIELD_MARKERS.iter().any(|marker| field.contains(marker)) {
+                    return format!("{}: [redacted]", k);
+                }
+                let value = match v {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                };
+                format!("{}: {}", k, truncate_chars(&value, SNAPSHOT_VALUE_CHARS))
+            })
+            .collect();
+        RecordSnapshot {
+            source_index,
+            content_hash: format!("{:016x}", content_hash),
+            preview: truncate_chars(&preview.join(", "), SNAPSHOT_PREVIEW_CHARS),
+        }
+    }
+}
+
+impl fmt::Display for RecordSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source_index {
+            Some(source_index) => write!(f, "record {}", source_index)?,
+            None => write!(f, "record")?,
+        }
+        write!(f, " (hash {}, {{{}}})", self.content_hash, self.preview)
+    }
+}
+
+impl InvalidRecordPolicy {
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: Position of the element in the message, used in logs and errors
+    /// * `element`: An element of the message's array
+    ///
+    /// returns: Result<Option<Map<String, Value>>, ProcessError> the record, None if the element
+    /// is skipped
+    pub fn to_record(
+        self,
+        index: usize,
+        element: Value,
+    ) -> Result<Option<Map<String, Value>>, ProcessError> {
+        let element = match element {
+            Value::Object(record) => return Ok(Some(record)),
+            other => other,
+        };
+        match self {
+            Self::Skip => {
+                warn!(record = index, "Skipping record that is not a JSON object");
+                Ok(None)
+            }
+            Self::Fail => Err(ProcessError::InvalidRecord(index)),
+            Self::Coerce => match element {
+                Value::Null => {
+                    warn!(record = index, "Skipping null record");
+                    Ok(None)
+                }
+                Value::String(text) => match serde_json::from_str::<Value>(&text) {
+                    Ok(Value::Object(record)) => Ok(Some(record)),
+                    _ => Ok(Some(Map::from_iter([("value".to_string(), Value::String(text))]))),
+                },
+                other => Ok(Some(Map::from_iter([("value".to_string(), other)]))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_record(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn objects_are_records_under_every_policy() {
+        let policies = [
+            InvalidRecordPolicy::Skip,
+            InvalidRecordPolicy::Fail,
+            InvalidRecordPolicy::Coerce,
+        ];
+        for policy in policies {
+            let record = policy.to_record(0, json!({"title": "Lamp"})).unwrap();
+            assert_eq!(record, Some(to_record(json!({"title": "Lamp"}))));
+        }
+    }
+
+    #[test]
+    fn skip_drops_elements_that_are_not_objects() {
+        let record = InvalidRecordPolicy::Skip.to_record(1, json!(42)).unwrap();
+        assert_eq!(record, None);
+    }
+
+    #[test]
+    fn fail_names_the_index_of_the_element() {
+        let result = InvalidRecordPolicy::Fail.to_record(3, json!("Lamp"));
+        assert!(matches!(result, Err(ProcessError::InvalidRecord(3))));
+    }
+
+    #[test]
+    fn coerce_turns_elements_into_records() {
+        let policy = InvalidRecordPolicy::Coerce;
+        assert_eq!(
+            policy.to_record(0, json!(42)).unwrap(),
+            Some(to_record(json!({"value": 42})))
+        );
+        // Strings holding a JSON object are parsed
+        assert_eq!(
+            policy.to_record(1, json!("{\"title\": \"Lamp\"}")).unwrap(),
+            Some(to_record(json!({"title": "Lamp"})))
+        );
+        assert_eq!(
+            policy.to_record(2, json!("Lamp")).unwrap(),
+            Some(to_record(json!({"value": "Lamp"})))
+        );
+        assert_eq!(policy.to_record(3, Value::Null).unwrap(), None);
+    }
+
+    fn snapshot_of(fields: &[(&str, HashMapValues)]) -> RecordSnapshot {
+        let record: HashMap<String, HashMapValues> = fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        RecordSnapshot::from_record(&record)
+    }
+
+    #[test]
+    fn fields_that_look_like_secrets_are_redacted() {
+        let snapshot = snapshot_of(&[
+            ("title", HashMapValues::Str("Lamp".to_string())),
+            ("API_KEY", HashMapValues::Str("sk-live-123".to_string())),
+            ("user_password", HashMapValues::Str("hunter2".to_string())),
+            ("source_index", HashMapValues::Int(2)),
+        ]);
+        assert_eq!(snapshot.source_index, Some(2));
+        assert_eq!(
+            snapshot.preview,
+            "API_KEY: [redacted], title: Lamp, user_password: [redacted]"
+        );
+    }
+
+    #[test]
+    fn long_values_and_previews_are_truncated() {
+        let snapshot = snapshot_of(&[("text", HashMapValues::Str("a".repeat(100)))]);
+        assert_eq!(
+            snapshot.preview,
+            format!("text: {}...", "a".repeat(SNAPSHOT_VALUE_CHARS))
+        );
+
+        let fields: Vec<(String, HashMapValues)> = (0..20)
+            .map(|i| (format!("field_{:02}", i), HashMapValues::Str("b".repeat(100))))
+            .collect();
+        let fields: Vec<(&str, HashMapValues)> =
+            fields.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+        let snapshot = snapshot_of(&fields);
+        assert!(snapshot.preview.ends_with("..."));
+        assert_eq!(snapshot.preview.chars().count(), SNAPSHOT_PREVIEW_CHARS + 3);
+    }
+
+    #[test]
+    fn the_content_hash_only_depends_on_the_content() {
+        let title = |title: &str| ("title", HashMapValues::Str(title.to_string()));
+        let price = ("price", HashMapValues::Int(12));
+        let lamp = [title("Lamp"), price.clone()];
+        let reordered = [price.clone(), title("Lamp")];
+        let desk = [title("Desk"), price];
+        assert_eq!(snapshot_of(&lamp).content_hash, snapshot_of(&reordered).content_hash);
+        assert_ne!(snapshot_of(&lamp).content_hash, snapshot_of(&desk).content_hash);
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Elements whose content is never readable text
+const SKIPPED_ELEMENTS: [&str; 2] = ["script", "style"];
+
+/// A single text transformation applied before embedding
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PreprocessingStep {
+    /// Removes HTML tags (and the content of `script` and `style` elements) and decodes the
+    /// common character entities
+    StripHtml,
+    /// Replaces runs of whitespace with a single space and trims the ends
+    CollapseWhitespace,
+    Lowercase,
+}
+
+impl PreprocessingStep {
+    fn parse(step: &str) -> Option<Self> {
+        match step.trim().to_lowercase().as_str() {
+            "strip_html" | "html" => Some(Self::StripHtml),
+            "collapse_whitespace" | "whitespace" => Some(Self::CollapseWhitespace),
+            "lowercase" => Some(Self::Lowercase),
+            _ => None,
+        }
+    }
+
+    fn apply(self, text: &str) -> String {
+        match self {
+            Self::StripHtml => strip_html(text),
+            Self::CollapseWhitespace => text.split_whitespace().collect::<Vec<_>>().join(" "),
+            Self::Lowercase => text.to_lowercase(),
+        }
+    }
+}
+
+/// Steps applied in order to text before it is embedded. The same preprocessor has to be used for
+/// documents and for queries, otherwise their embeddings aren't comparable
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TextPreprocessor {
+    pub steps: Vec<PreprocessingStep>,
+}
+
+impl From<&str> for TextPreprocessor {
+    /// Parses a comma separated list of steps, e.g. `strip_html,collapse_whitespace,lowercase`.
+    /// Unknown steps are ignored
+    fn from(steps: &str) -> Self {
+        TextPreprocessor {
+            steps: steps
+                .split(',')
+                .filter(|step| !step.trim().is_empty())
+                .filter_map(|step| {
+                    let parsed = PreprocessingStep::parse(step);
+                    if parsed.is_none() {
+                        warn!(step = step.trim(), "Ignoring unknown text preprocessing step");
+                    }
+                    parsed
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TextPreprocessor {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.steps
+            .iter()
+            .fold(text.to_string(), |text, step| step.apply(&text))
+    }
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => entity.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Tags are replaced with a space so that the text of adjacent elements doesn't run together
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut skipped_element: Option<&str> = None;
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            // A `<` not followed by a tag name (e.g. `a < b`) is just text
+            '<' if rest[1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!') =>
+            {
+                let Some(end) = rest.find('>') else {
+                    // So is an unclosed one
+                    if skipped_element.is_none() {
+                        text.push_str(rest);
+                    }
+                    break;
+                };
+                let tag = rest[1..end].trim().to_lowercase();
+                let name = tag
+                    .trim_start_matches('/')
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                match skipped_element {
+                    Some(element) if tag.starts_with('/') && name == element => {
+                        skipped_element = None
+                    }
+                    Some(_) => {}
+                    None => {
+                        if !tag.starts_with('/') && !tag.ends_with('/') {
+                            skipped_element =
+                                SKIPPED_ELEMENTS.iter().copied().find(|element| *element == name);
+                        }
+                        text.push(' ');
+                    }
+                }
+                rest = &rest[end + 1..];
+            }
+            '&' if skipped_element.is_none() => {
+                let decoded = rest[1..]
+                    .find(';')
+                    .filter(|end| *end <= 10)
+                    .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+                match decoded {
+                    Some((decoded, length)) => {
+                        text.push(decoded);
+                        rest = &rest[length..];
+                    }
+                    None => {
+                        text.push('&');
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            c => {
+                if skipped_element.is_none() {
+                    text.push(c);
+                }
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_is_reduced_to_its_readable_text() {
+        let preprocessor = TextPreprocessor::from("strip_html,collapse_whitespace,lowercase");
+        let html = "<p class=\"intro\">Fish &amp; <b>Chips</b></p>\n<script>track();</script>";
+        assert_eq!(preprocessor.apply(html), "fish & chips");
+    }
+
+    #[test]
+    fn steps_are_applied_in_order() {
+        let html = "<p>a</p><p>b</p>";
+        let strip_first = TextPreprocessor::from("strip_html,collapse_whitespace");
+        assert_eq!(strip_first.apply(html), "a b");
+        // The spaces left by the tags are never collapsed
+        let collapse_first = TextPreprocessor::from("collapse_whitespace,strip_html");
+        assert_eq!(collapse_first.apply(html), " a  b ");
+    }
+
+    #[test]
+    fn unknown_steps_are_ignored() {
+        let preprocessor = TextPreprocessor::from("lowercase, stemming,,");
+        assert_eq!(preprocessor.steps, vec![PreprocessingStep::Lowercase]);
+    }
+}
@@ -1,86 +1,1366 @@
+use anyhow::anyhow;
 use mongodb::Database;
-use std::sync::Arc;
-use tokio::sync::{RwLock};
 use qdrant_client::client::QdrantClient;
-use serde_json::Value;
+use qdrant_client::qdrant::vectors::VectorsOptions;
+use qdrant_client::qdrant::PointStruct;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use serde_json::{Map, Value};
+use tracing::{field, info, instrument, warn, Span};
 
+use crate::data::concurrency::{message_gate, MessageGate};
+use crate::data::errors::ProcessError;
+use crate::data::models::{stamp_source_index, InvalidRecordPolicy, MessageFormat};
+use crate::data::preprocessing::TextPreprocessor;
+use crate::data::schema_validation::validate_record;
+use crate::data::streaming::stream_json_records;
+use crate::idempotency::traits::IdempotencyStore;
+use crate::init::env_variables::GLOBAL_DATA;
 use crate::llm::models::EmbeddingModels;
-use crate::mongo::queries::get_embedding_model_and_embedding_key;
-use crate::qdrant::helpers::embed_payload;
+use crate::metrics::utils::{EMBEDDING_FAILURES, EMBEDDING_LATENCY, MESSAGES_PROCESSED};
+use crate::mongo::models::{Model, RecordSchema};
+use crate::mongo::queries::{get_datasource, get_embedding_model_and_embedding_key};
+use crate::qdrant::helpers::{
+    embed_table_chunks_async, lookup_datasource_tenant, point_id_to_string, point_source_index,
+};
+use crate::qdrant::models::{
+    DryRunReport, EmbeddingOptions, IdStrategy, OversizeBehavior, RetryPolicy, UpsertReport,
+    DENSE_VECTOR_NAME,
+};
 use crate::qdrant::utils::Qdrant;
-use crate::utils::conversions::convert_serde_value_to_hashmap_string;
+use crate::utils::conversions::{
+    convert_csv_to_records, convert_serde_value_to_hashmap_value, convert_string_to_model_overrides,
+};
+use crate::vector_store::traits::VectorStore;
+
+/// Everything about a datasource needed to turn its records into points
+struct IngestionSettings {
+    text_field: String,
+    embedding_model_name: String,
+    vector_length: u64,
+    options: EmbeddingOptions,
+    schema: Option<RecordSchema>,
+    skip_invalid: bool,
+}
+
+impl IngestionSettings {
+    /// Name of the dense vector points are upserted with
+    fn vector_name(&self) -> String {
+        match self.options.sparse_vectors {
+            true => DENSE_VECTOR_NAME.to_string(),
+            false => self.embedding_model_name.clone(),
+        }
+    }
+}
+
+/// The name and vector size of the datasource's embedding model. A model in `model_overrides`
+/// takes precedence over `configured_model`, the one the datasource was set up with
+fn resolve_embedding_model(
+    datasource_id: &str,
+    model_overrides: &HashMap<String, String>,
+    configured_model: Option<Model>,
+) -> Result<(String, u64), ProcessError> {
+    match model_overrides.get(datasource_id) {
+        Some(model_name) => {
+            // The collection's vector size has to come from the model itself
+            let Some(dimensions) = EmbeddingModels::from(model_name.clone()).dimensions() else {
+                warn!(model = %model_name, "Unknown embedding model override for datasource");
+                return Err(ProcessError::ModelNotFound(datasource_id.to_string()));
+            };
+            Ok((model_name.clone(), dimensions))
+        }
+        None => {
+            let Some(model_parameters) = configured_model else {
+                warn!("No embedding model found for datasource");
+                return Err(ProcessError::ModelNotFound(datasource_id.to_string()));
+            };
+            Ok((model_parameters.model, model_parameters.embeddingLength as u64))
+        }
+    }
+}
+
+/// Looks up the datasource's embedding model and settings. A model set for the datasource in
+/// `EMBEDDING_MODEL_OVERRIDES` takes precedence over the one it was configured with in the webapp
+async fn load_ingestion_settings(
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+) -> Result<IngestionSettings, ProcessError> {
+    let global_data = GLOBAL_DATA.read().await;
+    let mongodb_connection = mongo_conn.read().await;
+    let (model_parameter_result, embedding_field) =
+        get_embedding_model_and_embedding_key(&mongodb_connection, datasource_id)
+            .await
+            .map_err(|e| {
+                warn!(error = %e, "Embedding model lookup failed");
+                ProcessError::ModelLookupFailed(e)
+            })?;
+    let model_overrides =
+        convert_string_to_model_overrides(&global_data.embedding_model_overrides);
+    let (embedding_model_name, vector_length) =
+        resolve_embedding_model(datasource_id, &model_overrides, model_parameter_result)?;
+    let datasource = get_datasource(&mongodb_connection, datasource_id)
+        .await
+        .ok()
+        .flatten();
+    let schema = datasource
+        .as_ref()
+        .and_then(|datasource| datasource.recordSchema.clone());
+    let skip_invalid = datasource
+        .as_ref()
+        .and_then(|datasource| datasource.skipInvalidRecords)
+        .unwrap_or(false);
+    let embed_fields = datasource
+        .as_ref()
+        .and_then(|datasource| datasource.embedFields.clone())
+        .unwrap_or_default();
+    // Opt in per datasource, collections created without sparse vectors can't take them later
+    let sparse_vectors = datasource
+        .as_ref()
+        .and_then(|datasource| datasource.hybridSearch)
+        .unwrap_or(false);
+    let text_field = match embedding_field {
+        Some(text_field) => text_field,
+        // The embedding field isn't needed when the datasource lists the fields to embed
+        None if !embed_fields.is_empty() => String::new(),
+        None => {
+            warn!("No embedding field set for datasource");
+            return Err(ProcessError::NoEmbeddingField(datasource_id.to_string()));
+        }
+    };
+    // Datasources without a primary key field fall back to hashing the record
+    let id_strategy = IdStrategy::from(datasource.and_then(|datasource| datasource.primaryKeyField));
+    let options = EmbeddingOptions {
+        embed_fields,
+        id_strategy,
+        // Chunks can't be longer than the datasource's model takes
+        max_tokens: global_data
+            .embedding_max_tokens
+            .min(EmbeddingModels::from(embedding_model_name.clone()).max_input_tokens()),
+        overlap: global_data.embedding_chunk_overlap,
+        embedding_timeout: Duration::from_secs(global_data.embedding_timeout_secs),
+        normalize: global_data.embedding_normalize,
+        max_payload_bytes: global_data.max_payload_bytes,
+        oversize_behavior: OversizeBehavior::from(global_data.oversize_behavior.clone()),
+        max_concurrent_embeddings: global_data.max_concurrent_embeddings,
+        embedding_retry_policy: RetryPolicy::new(
+            global_data.embedding_max_retries,
+            global_data.embedding_retry_base_delay_ms,
+        ),
+        sparse_vectors,
+        preprocessor: TextPreprocessor::from(global_data.text_preprocessing.as_str()),
+    };
+    Ok(IngestionSettings {
+        text_field,
+        embedding_model_name,
+        vector_length,
+        options,
+        schema,
+        skip_invalid,
+    })
+}
+
+/// Checks each record against the datasource's schema. Invalid records are dropped (and logged)
+/// when `skip_invalid` is set, otherwise any invalid record fails the whole message.
+/// `first_index` is the position of the first record in the message, used in error messages
+fn validate_records(
+    records: Vec<Map<String, Value>>,
+    first_index: usize,
+    settings: &IngestionSettings,
+) -> Result<Vec<Map<String, Value>>, ProcessError> {
+    let Some(schema) = &settings.schema else {
+        return Ok(records);
+    };
+    let mut valid_records = Vec::with_capacity(records.len());
+    let mut violations = vec![];
+    for (index, record) in (first_index..).zip(records) {
+        match validate_record(&record, schema) {
+            Ok(()) => valid_records.push(record),
+            Err(record_violations) if settings.skip_invalid => {
+                warn!(record = index, violations = ?record_violations, "Skipping invalid record");
+            }
+            Err(record_violations) => violations.extend(
+                record_violations
+                    .into_iter()
+                    .map(|violation| format!("record {}: {}", index, violation)),
+            ),
+        }
+    }
+    if !violations.is_empty() {
+        warn!(?violations, "Message does not match the datasource schema");
+        return Err(ProcessError::SchemaViolation(violations));
+    }
+    Ok(valid_records)
+}
+
+/// Parses a message holding either a single JSON object or an array of JSON objects. Array
+/// elements that aren't objects are handled according to `on_invalid_record`, top level values
+/// that are neither an object nor an array hold no records
+fn parse_json_records(
+    message: &str,
+    on_invalid_record: InvalidRecordPolicy,
+) -> Result<Vec<Map<String, Value>>, ProcessError> {
+    let message_data: Value = match serde_json::from_str(message) {
+        Ok(message_data) => message_data,
+        Err(e) => {
+            warn!(error = %e, "Message could not be parsed as JSON");
+            return Err(ProcessError::InvalidJson(e));
+        }
+    };
+    let records = match message_data {
+        Value::Object(mut data_obj) => {
+            stamp_source_index(&mut data_obj, 0);
+            vec![data_obj]
+        }
+        Value::Array(data_array) => {
+            let mut records = Vec::with_capacity(data_array.len());
+            for (index, element) in data_array.into_iter().enumerate() {
+                if let Some(mut record) = on_invalid_record.to_record(index, element)? {
+                    stamp_source_index(&mut record, index);
+                    records.push(record);
+                }
+            }
+            records
+        }
+        _ => vec![],
+    };
+    Ok(records)
+}
+
+/// Parses a message into records, each stamped with its position in the message
+async fn parse_message(
+    message: &str,
+    message_format: MessageFormat,
+) -> Result<Vec<Map<String, Value>>, ProcessError> {
+    match message_format {
+        MessageFormat::Json => parse_json_records(message, invalid_record_policy().await),
+        MessageFormat::Csv => {
+            let mut records = convert_csv_to_records(message).map_err(|e| {
+                warn!(error = %e, "Message could not be parsed as CSV");
+                ProcessError::InvalidCsv(e)
+            })?;
+            for (index, record) in records.iter_mut().enumerate() {
+                stamp_source_index(record, index);
+            }
+            Ok(records)
+        }
+    }
+}
+
+async fn invalid_record_policy() -> InvalidRecordPolicy {
+    let global_data = GLOBAL_DATA.read().await;
+    InvalidRecordPolicy::from(global_data.on_invalid_record.clone())
+}
 
+/// Embeds records into points, timing the embedding requests
+async fn embed_records(
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+    records: Vec<Map<String, Value>>,
+    settings: &IngestionSettings,
+) -> Result<Vec<PointStruct>, ProcessError> {
+    let list_of_embedding_data: Vec<_> = records
+        .into_iter()
+        .map(convert_serde_value_to_hashmap_value)
+        .collect();
+    let embedding_timer = EMBEDDING_LATENCY
+        .with_label_values(&[datasource_id])
+        .start_timer();
+    let embedding_result = embed_table_chunks_async(
+        Arc::clone(mongo_conn),
+        datasource_id.to_string(),
+        list_of_embedding_data,
+        settings.text_field.as_str(),
+        EmbeddingModels::from(settings.embedding_model_name.clone()),
+        &settings.options,
+    )
+        .await;
+    embedding_timer.observe_duration();
+    let points = embedding_result.map_err(|e| {
+        warn!(error = %e, "Embedding records failed");
+        EMBEDDING_FAILURES.with_label_values(&[datasource_id]).inc();
+        // Errors with a dedicated variant (e.g. timeouts) are passed through as they are
+        match e.downcast::<ProcessError>() {
+            Ok(process_error) => process_error,
+            Err(e) => ProcessError::EmbeddingFailed(e),
+        }
+    })?;
+    // The collection is created with the model's configured length, so vectors of any other
+    // length (e.g. from a misconfigured model) must not reach it
+    let vector_name = settings.vector_name();
+    for point in &points {
+        if let Some(got) = dense_vector_dimension(point, &vector_name) {
+            if got as u64 != settings.vector_length {
+                warn!(
+                    expected = settings.vector_length,
+                    got, "Embedding model returned vectors of an unexpected size"
+                );
+                return Err(ProcessError::DimensionMismatch {
+                    expected: settings.vector_length,
+                    got: got as u64,
+                });
+            }
+        }
+    }
+    Ok(points)
+}
+
+async fn upsert_points(
+    vector_store: &Arc<dyn VectorStore>,
+    points: Vec<PointStruct>,
+    settings: &IngestionSettings,
+) -> Result<UpsertReport, ProcessError> {
+    vector_store
+        .bulk_upsert(
+            points,
+            Some(settings.vector_length),
+            Some(settings.vector_name()),
+        )
+        .await
+        .map_err(|e| {
+            warn!(error = %e, "Upserting points to the vector store failed");
+            match e.downcast::<ProcessError>() {
+                Ok(process_error) => process_error,
+                Err(e) => ProcessError::UpsertFailed(e),
+            }
+        })
+}
+
+/// Parses an incoming message (a single JSON object, an array of JSON objects or CSV with a
+/// header row, depending on `message_format`), embeds
+/// each record and upserts the resulting points to the datasource's collection. The whole message
+/// is held in memory, see `process_messages_streaming` for very large messages.
+///
+/// When a `message_id` is given, a message that was already processed successfully (e.g. one
+/// redelivered by the broker) returns an empty report straight away. Every message waits for a
+/// slot in `message_gate` first, so at most `max_concurrent_messages` are ingested at once.
+///
+/// returns: Result<UpsertReport, ProcessError> which points were written and which failed. It is
+/// up to the caller to decide whether a partial ingestion is acceptable
+#[instrument(
+    skip(vector_store, mongo_conn, message, idempotency_store),
+    fields(datasource_id = %datasource_id, record_count = field::Empty)
+)]
 pub async fn process_messages(
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    vector_store: Arc<dyn VectorStore>,
     mongo_conn: Arc<RwLock<Database>>,
     message: String,
+    message_format: MessageFormat,
     datasource_id: String,
-) {
-    // initiate variables
-    let mongodb_connection = mongo_conn.read().await;
+    message_id: Option<String>,
+    idempotency_store: Arc<dyn IdempotencyStore>,
+) -> Result<UpsertReport, ProcessError> {
+    let incoming = IncomingMessage {
+        message,
+        message_format,
+        datasource_id,
+        message_id,
+    };
+    let load_settings = |datasource_id: String| {
+        let mongo_conn = Arc::clone(&mongo_conn);
+        async move { load_ingestion_settings(&mongo_conn, &datasource_id).await }
+    };
+    process_messages_with(
+        vector_store,
+        &mongo_conn,
+        incoming,
+        idempotency_store,
+        message_gate().await,
+        load_settings,
+    )
+        .await
+}
+
+/// A message handed over by the consumer, along with what it needs to be ingested
+struct IncomingMessage {
+    message: String,
+    message_format: MessageFormat,
+    datasource_id: String,
+    message_id: Option<String>,
+}
+
+/// `process_messages` holding a slot of `gate` while the message is ingested, with the
+/// datasource's settings coming from `load_settings`
+async fn process_messages_with<F, Fut>(
+    vector_store: Arc<dyn VectorStore>,
+    mongo_conn: &Arc<RwLock<Database>>,
+    incoming: IncomingMessage,
+    idempotency_store: Arc<dyn IdempotencyStore>,
+    gate: &MessageGate,
+    load_settings: F,
+) -> Result<UpsertReport, ProcessError>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<IngestionSettings, ProcessError>>,
+{
+    let IncomingMessage {
+        message,
+        message_format,
+        datasource_id,
+        message_id,
+    } = incoming;
+    MESSAGES_PROCESSED.with_label_values(&[&datasource_id]).inc();
+    // Message ids are only unique per producer, so they are scoped by datasource
+    let idempotency_key = message_id.map(|message_id| format!("{}:{}", datasource_id, message_id));
+    if let Some(key) = &idempotency_key {
+        match idempotency_store.is_processed(key).await {
+            Ok(true) => {
+                info!(message_id = %key, "Message was already processed, skipping");
+                return Ok(UpsertReport::default());
+            }
+            Ok(false) => {}
+            // Processing twice is safer than dropping the message
+            Err(e) => warn!(error = %e, "Could not check whether message was already processed"),
+        }
+    }
+    // Held until the message is fully ingested. Taken after the idempotency check so that
+    // redeliveries don't wait behind messages that still have to be ingested
+    let _permit = gate.acquire().await;
     // let redis_connection = redis_connection_pool.lock().await;
-    match serde_json::from_str(message.as_str()) {
-        Ok::<Value, _>(message_data) => {
-            match get_embedding_model_and_embedding_key(&mongodb_connection, datasource_id.as_str())
+    let records = parse_message(&message, message_format).await?;
+    Span::current().record("record_count", records.len());
+    if records.is_empty() {
+        warn!("Message contained no records");
+        return Ok(UpsertReport::default());
+    }
+    let settings = load_settings(datasource_id.clone()).await?;
+    let records = validate_records(records, 0, &settings)?;
+    if records.is_empty() {
+        warn!("No records left to embed after schema validation");
+        return Ok(UpsertReport::default());
+    }
+    let point_structs = embed_records(mongo_conn, &datasource_id, records, &settings).await?;
+    let report = upsert_points(&vector_store, point_structs, &settings).await?;
+    if let Some(key) = &idempotency_key {
+        // A partially failed message has to be processed again when it is redelivered
+        if report.failed.is_empty() {
+            if let Err(e) = idempotency_store.mark_processed(key).await {
+                warn!(error = %e, "Could not record message as processed");
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Size of the point's dense vector
+fn dense_vector_dimension(point: &PointStruct, vector_name: &str) -> Option<usize> {
+    match point.vectors.as_ref()?.vectors_options.as_ref()? {
+        VectorsOptions::Vector(vector) => Some(vector.data.len()),
+        VectorsOptions::Vectors(named_vectors) => {
+            named_vectors.vectors.get(vector_name).map(|vector| vector.data.len())
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `message`: The message to validate, in the same formats `process_messages` accepts
+/// * `message_format`: Whether the message is JSON or CSV
+/// * `datasource_id`: Datasource whose embedding model and schema the message is checked against
+///
+/// Dry run of `process_messages`: the message is parsed, checked against the datasource's schema
+/// and embedded but nothing is written to the vector store. Records that violate the schema are
+/// listed in the report rather than failing the whole message, and are left out of the points
+/// count. Embedding requests are made as usual, so this costs as much as real ingestion
+///
+/// returns: Result<DryRunReport, ProcessError>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[instrument(skip(mongo_conn, message), fields(datasource_id = %datasource_id))]
+pub async fn validate_message(
+    mongo_conn: Arc<RwLock<Database>>,
+    message: String,
+    message_format: MessageFormat,
+    datasource_id: String,
+) -> Result<DryRunReport, ProcessError> {
+    let records = parse_message(&message, message_format).await?;
+    let settings = load_ingestion_settings(&mongo_conn, &datasource_id).await?;
+    let mut report = DryRunReport {
+        records: records.len(),
+        expected_dimension: settings.vector_length,
+        ..Default::default()
+    };
+    let records: Vec<Map<String, Value>> = match &settings.schema {
+        Some(schema) => records
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, record)| match validate_record(&record, schema) {
+                Ok(()) => Some(record),
+                Err(violations) => {
+                    let source_index = record.get("source_index").cloned().unwrap_or(index.into());
+                    report.invalid_records.extend(
+                        violations
+                            .into_iter()
+                            .map(|violation| format!("record {}: {}", source_index, violation)),
+                    );
+                    None
+                }
+            })
+            .collect(),
+        None => records,
+    };
+    if records.is_empty() {
+        return Ok(report);
+    }
+    let points = embed_records(&mongo_conn, &datasource_id, records, &settings).await?;
+    report.points = points.len();
+    report.vector_dimension = points
+        .first()
+        .and_then(|point| dense_vector_dimension(point, &settings.vector_name()));
+    Ok(report)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `reader`: The message, a single JSON object or an array of JSON objects
+/// * `batch_size`: Number of records embedded and upserted together
+///
+/// Like `process_messages` but the message is parsed incrementally. Records flow through bounded
+/// channels from the parser to the embedding stage and on to the upsert stage, so memory use
+/// depends on `batch_size` rather than on the size of the message and a slow stage holds back
+/// the ones before it. Batches upserted before an error are not rolled back. Takes a slot in
+/// `message_gate` for the whole message, like `process_messages`.
+///
+/// returns: Result<UpsertReport, ProcessError>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[instrument(
+    skip(vector_store, mongo_conn, reader),
+    fields(datasource_id = %datasource_id, record_count = field::Empty)
+)]
+pub async fn process_messages_streaming<R: Read + Send + 'static>(
+    vector_store: Arc<dyn VectorStore>,
+    mongo_conn: Arc<RwLock<Database>>,
+    reader: R,
+    datasource_id: String,
+    batch_size: usize,
+) -> Result<UpsertReport, ProcessError> {
+    let _permit = message_gate().await.acquire().await;
+    MESSAGES_PROCESSED.with_label_values(&[&datasource_id]).inc();
+    let settings = load_ingestion_settings(&mongo_conn, &datasource_id).await?;
+    let batch_size = batch_size.max(1);
+    let (record_sender, mut record_receiver) = mpsc::channel(batch_size);
+    let on_invalid_record = invalid_record_policy().await;
+    let parser = tokio::task::spawn_blocking(move || {
+        stream_json_records(reader, record_sender, on_invalid_record)
+    });
+    // One batch of points can wait to be upserted while the next one is embedded
+    let (point_sender, mut point_receiver) = mpsc::channel::<Vec<PointStruct>>(1);
+    let embed_stage = async {
+        let mut record_count = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            let record = record_receiver.recv().await;
+            let finished = record.is_none();
+            batch.extend(record);
+            if batch.len() < batch_size && !finished {
+                continue;
+            }
+            let first_index = record_count;
+            record_count += batch.len();
+            let records = validate_records(std::mem::take(&mut batch), first_index, &settings)?;
+            if !records.is_empty() {
+                let points = embed_records(&mongo_conn, &datasource_id, records, &settings).await?;
+                if point_sender.send(points).await.is_err() {
+                    // The upsert stage failed, its error is the one reported
+                    break;
+                }
+            }
+            if finished {
+                break;
+            }
+        }
+        drop(point_sender);
+        Ok::<usize, ProcessError>(record_count)
+    };
+    let upsert_stage = async {
+        let mut report = UpsertReport::default();
+        while let Some(points) = point_receiver.recv().await {
+            let batch_report = upsert_points(&vector_store, points, &settings).await?;
+            report.succeeded += batch_report.succeeded;
+            report.failed.extend(batch_report.failed);
+        }
+        Ok::<UpsertReport, ProcessError>(report)
+    };
+    let (embed_result, upsert_result) = tokio::join!(embed_stage, upsert_stage);
+    // Dropping the receiver unblocks the parser if a stage stopped early
+    drop(record_receiver);
+    let parse_result = parser.await;
+    let record_count = embed_result?;
+    let report = upsert_result?;
+    match parse_result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            warn!(error = %e, "Message could not be parsed as JSON");
+            return Err(ProcessError::InvalidJson(e));
+        }
+        Err(e) => {
+            return Err(ProcessError::EmbeddingFailed(anyhow!("Message parser panicked: {}", e)))
+        }
+    }
+    Span::current().record("record_count", record_count);
+    if record_count == 0 {
+        warn!("Message contained no records");
+    }
+    Ok(report)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `qdrant_conn`: Client shared by every datasource in the batch
+/// * `messages`: JSON message bodies along with the datasource each one belongs to
+///
+/// Messages are grouped by datasource so that each datasource's settings are looked up, and its
+/// collection checked, only once. The records of all of a datasource's messages are embedded in
+/// one pass and their points upserted together. Each datasource's messages are ingested as a
+/// single unit, holding one slot of `message_gate` while they are.
+///
+/// returns: Vec<Result<usize, ProcessError>> one result per message, in the order given, holding
+/// the number of points upserted for that message
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[instrument(skip_all, fields(message_count = messages.len()))]
+pub async fn process_messages_batch(
+    qdrant_conn: Arc<QdrantClient>,
+    mongo_conn: Arc<RwLock<Database>>,
+    messages: Vec<(String, String)>,
+) -> Vec<Result<usize, ProcessError>> {
+    let prepare_datasource = |datasource_id: String| {
+        let qdrant_conn = Arc::clone(&qdrant_conn);
+        let mongo_conn = Arc::clone(&mongo_conn);
+        async move {
+            let settings = load_ingestion_settings(&mongo_conn, &datasource_id).await?;
+            let tenant_id = lookup_datasource_tenant(&mongo_conn, &datasource_id)
                 .await
-            {
-                Ok((model_parameter_result, embedding_field)) => match model_parameter_result {
-                    Some(model_parameters) => {
-                        let vector_length = model_parameters.embeddingLength as u64;
-                        let embedding_model_name = model_parameters.model;
-                        let embedding_model_name_clone = embedding_model_name.clone();
-                        let ds_clone = datasource_id.clone();
-                        let qdrant = Qdrant::new(qdrant_conn, datasource_id);
-                        if let Value::Object(data_obj) = message_data {
-                            let mut metadata = convert_serde_value_to_hashmap_string(data_obj);
-                            if let Some(text_field) = embedding_field {
-                                println!("text field: {}", text_field.as_str());
-                                let text = metadata.remove(text_field.as_str()).unwrap();
-                                metadata.insert("page_content".to_string(), text.to_owned());
-                                let mongo_conn_clone = Arc::clone(&mongo_conn);
-                                match embed_payload(
-                                    mongo_conn_clone,
-                                    &metadata,
-                                    &text,
-                                    Some(ds_clone),
-                                    EmbeddingModels::from(embedding_model_name),
-                                )
-                                    .await
-                                {
-                                    Ok(point_struct) => {
-                                        if let Ok(_bulk_upload_result) =
-                                            qdrant
-                                                .upsert_data_point_blocking(
-                                                    point_struct,
-                                                    Some(vector_length),
-                                                    Some(embedding_model_name_clone),
-                                                )
-                                                .await
-                                        {
-                                            // let _ = redis_connection.increment_count(&"some_key".to_string(), 1);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("An error occurred while upserting  point structs to Qdrant: {}", e);
-                                    }
-                                }
-                            }
-                        }
+                .map_err(ProcessError::TenantLookupFailed)?;
+            let vector_store: Arc<dyn VectorStore> =
+                Arc::new(Qdrant::from_global_data(qdrant_conn, datasource_id, tenant_id).await);
+            Ok((vector_store, settings))
+        }
+    };
+    process_messages_batch_with(&mongo_conn, messages, prepare_datasource).await
+}
+
+/// `process_messages_batch` with the vector store and settings of each datasource coming from
+/// `prepare_datasource`
+async fn process_messages_batch_with<F, Fut>(
+    mongo_conn: &Arc<RwLock<Database>>,
+    messages: Vec<(String, String)>,
+    prepare_datasource: F,
+) -> Vec<Result<usize, ProcessError>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<(Arc<dyn VectorStore>, IngestionSettings), ProcessError>>,
+{
+    let mut results: Vec<Option<Result<usize, ProcessError>>> =
+        messages.iter().map(|_| None).collect();
+    let mut messages_by_datasource: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+    for (index, (message, datasource_id)) in messages.into_iter().enumerate() {
+        messages_by_datasource
+            .entry(datasource_id)
+            .or_default()
+            .push((index, message));
+    }
+    for (datasource_id, datasource_messages) in messages_by_datasource {
+        // Held until the datasource's messages are ingested
+        let _permit = message_gate().await.acquire().await;
+        for _ in &datasource_messages {
+            MESSAGES_PROCESSED.with_label_values(&[&datasource_id]).inc();
+        }
+        let datasource_results = match prepare_datasource(datasource_id.clone()).await {
+            Ok((vector_store, settings)) => {
+                process_datasource_messages(
+                    &vector_store,
+                    mongo_conn,
+                    &datasource_id,
+                    datasource_messages,
+                    &settings,
+                )
+                    .await
+            }
+            Err(e) => datasource_messages
+                .into_iter()
+                .map(|(index, _)| (index, Err(e.duplicate())))
+                .collect(),
+        };
+        for (index, result) in datasource_results {
+            results[index] = Some(result);
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or(Ok(0)))
+        .collect()
+}
+
+/// Processes every message of a single datasource for `process_messages_batch`, returning each
+/// message's result along with its index in the batch
+async fn process_datasource_messages(
+    vector_store: &Arc<dyn VectorStore>,
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+    messages: Vec<(usize, String)>,
+    settings: &IngestionSettings,
+) -> Vec<(usize, Result<usize, ProcessError>)> {
+    let on_invalid_record = invalid_record_policy().await;
+    let mut results = vec![];
+    let mut message_records: Vec<(usize, Vec<Map<String, Value>>)> = vec![];
+    for (index, message) in messages {
+        match parse_json_records(&message, on_invalid_record)
+            .and_then(|records| validate_records(records, 0, settings))
+        {
+            Ok(records) => message_records.push((index, records)),
+            Err(e) => results.push((index, Err(e))),
+        }
+    }
+    let message_points =
+        embed_message_records(mongo_conn, datasource_id, message_records, settings).await;
+    let mut embedded: Vec<(usize, HashSet<String>)> = vec![];
+    let mut points: Vec<PointStruct> = vec![];
+    for (index, message_result) in message_points {
+        match message_result {
+            Ok(message_points) => {
+                let ids = message_points
+                    .iter()
+                    .map(|point| point_id_to_string(&point.id))
+                    .collect();
+                embedded.push((index, ids));
+                points.extend(message_points);
+            }
+            Err(e) => results.push((index, Err(e))),
+        }
+    }
+    if points.is_empty() {
+        results.extend(embedded.into_iter().map(|(index, _)| (index, Ok(0))));
+        return results;
+    }
+    match upsert_points(vector_store, points, settings).await {
+        Ok(report) => {
+            let failed: HashSet<String> = report.failed.into_iter().map(|f| f.id).collect();
+            for (index, ids) in embedded {
+                let succeeded = ids.iter().filter(|id| !failed.contains(*id)).count();
+                results.push((index, Ok(succeeded)));
+            }
+        }
+        Err(e) => {
+            for (index, _) in embedded {
+                results.push((index, Err(e.duplicate())));
+            }
+        }
+    }
+    results
+}
+
+/// Embeds the records of several messages of a datasource in one pass and hands each message
+/// back its own points. For the duration of the pass each record's `source_index` holds its
+/// position in the whole batch, which is how its points are traced back to their message. If the
+/// pass fails the messages are embedded one at a time, so that the failure is attributed to the
+/// message that caused it (records embedded before the failure are served from the cache)
+async fn embed_message_records(
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+    message_records: Vec<(usize, Vec<Map<String, Value>>)>,
+    settings: &IngestionSettings,
+) -> Vec<(usize, Result<Vec<PointStruct>, ProcessError>)> {
+    // The message and the original `source_index` of each record in the batch
+    let mut record_origins: Vec<(usize, Option<u64>)> = vec![];
+    let mut batch_records = vec![];
+    for (index, records) in &message_records {
+        for record in records {
+            let mut record = record.clone();
+            let source_index = record.get("source_index").and_then(Value::as_u64);
+            stamp_source_index(&mut record, record_origins.len());
+            record_origins.push((*index, source_index));
+            batch_records.push(record);
+        }
+    }
+    let mut points_by_message: HashMap<usize, Vec<PointStruct>> = message_records
+        .iter()
+        .map(|(index, _)| (*index, vec![]))
+        .collect();
+    if batch_records.is_empty() {
+        return points_by_message
+            .into_iter()
+            .map(|(index, points)| (index, Ok(points)))
+            .collect();
+    }
+    match embed_records(mongo_conn, datasource_id, batch_records, settings).await {
+        Ok(points) => {
+            for mut point in points {
+                let origin = point_source_index(&point)
+                    .and_then(|batch_index| record_origins.get(batch_index as usize));
+                let Some((index, source_index)) = origin else {
+                    warn!(point_id = %point_id_to_string(&point.id), "Point has no batch position");
+                    continue;
+                };
+                match source_index {
+                    Some(source_index) => {
+                        point
+                            .payload
+                            .insert("source_index".to_string(), (*source_index as i64).into());
                     }
                     None => {
-                        eprintln!("Model mongo object returned None!");
+                        point.payload.remove("source_index");
                     }
-                },
-                Err(e) => {
-                    println!("An error occurred: {}", e);
+                }
+                if let Some(message_points) = points_by_message.get_mut(index) {
+                    message_points.push(point);
                 }
             }
+            points_by_message
+                .into_iter()
+                .map(|(index, points)| (index, Ok(points)))
+                .collect()
         }
         Err(e) => {
-            eprintln!(
-                "An error occurred while attempting to convert message to JSON: {}",
-                e
+            warn!(error = %e, "Embedding the batch failed, embedding its messages one at a time");
+            let mut results = vec![];
+            for (index, records) in message_records {
+                let points = embed_records(mongo_conn, datasource_id, records, settings).await;
+                results.push((index, points));
+            }
+            results
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongo::models::JsonType;
+    use crate::vector_store::test_utils::InMemoryVectorStore;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use mongodb::bson::oid::ObjectId;
+    use qdrant_client::qdrant::PointId;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn test_settings(schema: Option<RecordSchema>, skip_invalid: bool) -> IngestionSettings {
+        IngestionSettings {
+            text_field: "text".to_string(),
+            embedding_model_name: "text-embedding-3-small".to_string(),
+            vector_length: 1536,
+            options: EmbeddingOptions::default(),
+            schema,
+            skip_invalid,
+        }
+    }
+
+    fn title_schema() -> RecordSchema {
+        RecordSchema {
+            requiredFields: HashMap::from([("title".to_string(), JsonType::String)]),
+        }
+    }
+
+    fn to_records(value: Value) -> Vec<Map<String, Value>> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| record.as_object().unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn invalid_records_fail_the_message() {
+        let records = to_records(json!([{"title": "Lamp"}, {"title": 3}, {}]));
+        let settings = test_settings(Some(title_schema()), false);
+        let Err(ProcessError::SchemaViolation(violations)) =
+            validate_records(records, 0, &settings)
+        else {
+            panic!("expected a schema violation");
+        };
+        assert_eq!(
+            violations,
+            vec![
+                "record 1: title: expected string, found integer".to_string(),
+                "record 2: title: missing".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_records_are_skipped_when_skip_invalid_is_set() {
+        let records = to_records(json!([{"title": "Lamp"}, {"title": 3}, {"title": "Desk"}]));
+        let settings = test_settings(Some(title_schema()), true);
+        let valid_records = validate_records(records, 0, &settings).unwrap();
+        assert_eq!(valid_records.len(), 2);
+        assert_eq!(valid_records[1]["title"], "Desk");
+    }
+
+    #[derive(Default)]
+    struct InMemoryIdempotencyStore {
+        keys: Mutex<HashSet<String>>,
+    }
+
+    #[async_trait]
+    impl IdempotencyStore for InMemoryIdempotencyStore {
+        async fn is_processed(&self, key: &str) -> Result<bool> {
+            Ok(self.keys.lock().unwrap().contains(key))
+        }
+
+        async fn mark_processed(&self, key: &str) -> Result<()> {
+            self.keys.lock().unwrap().insert(key.to_string());
+            Ok(())
+        }
+    }
+
+    // The client only connects once it is used
+    async fn unused_mongo() -> Arc<RwLock<Database>> {
+        let client = mongodb::Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+        Arc::new(RwLock::new(client.database("test")))
+    }
+
+    #[tokio::test]
+    async fn redelivered_messages_return_without_being_ingested_again() {
+        let settings = test_settings(None, false);
+        let texts = ["redelivered refund", "redelivered exchange"];
+        warm_embedding_cache(&settings, &texts).await;
+        let idempotency_store = Arc::new(InMemoryIdempotencyStore::default());
+        let vector_store = Arc::new(InMemoryVectorStore::default());
+        let mongo_conn = unused_mongo().await;
+        let settings_loads = AtomicUsize::new(0);
+        let gate = MessageGate::new(1);
+        let deliver = || {
+            let incoming = IncomingMessage {
+                message: json!([{"text": texts[0]}, {"text": texts[1]}]).to_string(),
+                message_format: MessageFormat::Json,
+                datasource_id: "ds".to_string(),
+                message_id: Some("message-1".to_string()),
+            };
+            process_messages_with(
+                vector_store.clone(),
+                &mongo_conn,
+                incoming,
+                idempotency_store.clone(),
+                &gate,
+                |_| async {
+                    settings_loads.fetch_add(1, Ordering::SeqCst);
+                    Ok(test_settings(None, false))
+                },
+            )
+        };
+        let first = deliver().await.unwrap();
+        assert_eq!(first.succeeded, 2);
+        assert_eq!(vector_store.points.lock().unwrap().len(), 2);
+
+        // Neither the datasource's settings nor the embedding model are looked up again
+        let redelivery = tokio::time::timeout(Duration::from_secs(1), deliver())
+            .await
+            .expect("a redelivery should return straight away")
+            .unwrap();
+        assert_eq!(redelivery.succeeded, 0);
+        assert!(redelivery.failed.is_empty());
+        assert_eq!(vector_store.points.lock().unwrap().len(), 2);
+        assert_eq!(settings_loads.load(Ordering::SeqCst), 1);
+    }
+
+    /// Caches an embedding for each text so that embedding them never reaches the model
+    async fn warm_embedding_cache(settings: &IngestionSettings, texts: &[&str]) {
+        let model = EmbeddingModels::from(settings.embedding_model_name.clone());
+        for (i, text) in texts.iter().enumerate() {
+            let embedding = vec![i as f32; settings.vector_length as usize];
+            crate::llm::cache::embedding_cache().await.insert(
+                model.to_str().unwrap(),
+                &settings.options.preprocessor.apply(text),
+                embedding,
             );
         }
     }
+
+    fn sorted_by_id(mut points: Vec<PointStruct>) -> Vec<PointStruct> {
+        points.sort_by_key(|point| point_id_to_string(&point.id));
+        points
+    }
+
+    #[tokio::test]
+    async fn csv_messages_produce_the_same_points_as_their_json_equivalent() {
+        let csv = "title,price,quantity\nLamp,12.5,3\nDesk,80,1\n";
+        let json = json!([
+            {"title": "Lamp", "price": 12.5, "quantity": 3},
+            {"title": "Desk", "price": 80, "quantity": 1}
+        ])
+            .to_string();
+        let csv_records = parse_message(csv, MessageFormat::Csv).await.unwrap();
+        let json_records = parse_message(&json, MessageFormat::Json).await.unwrap();
+        assert_eq!(csv_records, json_records);
+
+        let settings = test_settings(None, false);
+        let settings = IngestionSettings {
+            text_field: "title".to_string(),
+            ..settings
+        };
+        warm_embedding_cache(&settings, &["Lamp", "Desk"]).await;
+        let mongo_conn = unused_mongo().await;
+        let csv_points = embed_records(&mongo_conn, "ds", csv_records, &settings).await.unwrap();
+        let json_points = embed_records(&mongo_conn, "ds", json_records, &settings).await.unwrap();
+        assert_eq!(csv_points.len(), 2);
+        assert_eq!(sorted_by_id(csv_points), sorted_by_id(json_points));
+    }
+
+    #[tokio::test]
+    async fn embeddings_of_the_wrong_size_are_a_dimension_mismatch() {
+        let model = EmbeddingModels::OAI_SMALL;
+        let settings = test_settings(None, false);
+        // A model returning 512 dimensions for a datasource configured with 1536
+        let text = "Shipping takes two weeks";
+        crate::llm::cache::embedding_cache().await.insert(
+            model.to_str().unwrap(),
+            &settings.options.preprocessor.apply(text),
+            vec![0.1; 512],
+        );
+        let records = to_records(json!([{"text": text}]));
+        let result = embed_records(&unused_mongo().await, "ds", records, &settings).await;
+        assert!(matches!(
+            result,
+            Err(ProcessError::DimensionMismatch { expected: 1536, got: 512 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn batches_mixing_datasources_attribute_results_to_each_message() {
+        let settings = test_settings(None, false);
+        let texts = ["batch lamp", "batch desk", "batch chair", "batch shelf"];
+        warm_embedding_cache(&settings, &texts).await;
+        let stores: HashMap<String, Arc<InMemoryVectorStore>> = ["ds_a", "ds_b"]
+            .into_iter()
+            .map(|datasource_id| (datasource_id.to_string(), Arc::default()))
+            .collect();
+        let messages = vec![
+            (json!([{"text": texts[0]}, {"text": texts[1]}]).to_string(), "ds_a".to_string()),
+            (json!({"text": texts[2]}).to_string(), "ds_b".to_string()),
+            ("not json".to_string(), "ds_a".to_string()),
+            (json!([{"text": texts[3]}]).to_string(), "ds_b".to_string()),
+        ];
+        let results = process_messages_batch_with(&unused_mongo().await, messages, |datasource_id| {
+            let vector_store: Arc<dyn VectorStore> = stores[&datasource_id].clone();
+            async move { Ok((vector_store, test_settings(None, false))) }
+        })
+            .await;
+
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[0], Ok(2)));
+        assert!(matches!(results[1], Ok(1)));
+        assert!(matches!(results[2], Err(ProcessError::InvalidJson(_))));
+        assert!(matches!(results[3], Ok(1)));
+        assert_eq!(stores["ds_a"].points.lock().unwrap().len(), 2);
+        let ds_b_points = stores["ds_b"].points.lock().unwrap();
+        assert_eq!(ds_b_points.len(), 2);
+        // Points keep the position of their record in their own message
+        assert!(ds_b_points.iter().all(|point| point_source_index(point) == Some(0)));
+    }
+
+    #[test]
+    fn non_object_elements_are_handled_according_to_the_policy() {
+        let message = json!([{"title": "Lamp"}, 42, {"title": "Desk"}]).to_string();
+        let records = parse_json_records(&message, InvalidRecordPolicy::Skip).unwrap();
+        // Records keep their position in the message
+        let source_indexes: Vec<&Value> = records.iter().map(|r| &r["source_index"]).collect();
+        assert_eq!(source_indexes, vec![&json!(0), &json!(2)]);
+
+        let result = parse_json_records(&message, InvalidRecordPolicy::Fail);
+        assert!(matches!(result, Err(ProcessError::InvalidRecord(1))));
+
+        let records = parse_json_records(&message, InvalidRecordPolicy::Coerce).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1]["value"], json!(42));
+    }
+
+    #[tokio::test]
+    async fn points_carry_the_position_of_their_record_in_the_message() {
+        let settings = test_settings(None, false);
+        let texts = ["traced lamp", "traced desk", "traced chair"];
+        warm_embedding_cache(&settings, &texts).await;
+        let message = json!([{"text": texts[0]}, {"text": texts[1]}, {"text": texts[2]}]);
+        let records = parse_message(&message.to_string(), MessageFormat::Json).await.unwrap();
+        let points = embed_records(&unused_mongo().await, "ds", records, &settings).await.unwrap();
+        let mut source_indexes: Vec<Option<u64>> = points.iter().map(point_source_index).collect();
+        source_indexes.sort();
+        assert_eq!(source_indexes, vec![Some(0), Some(1), Some(2)]);
+    }
+
+    fn configured_model(model: &str, embedding_length: i32) -> Model {
+        Model {
+            _id: ObjectId::new(),
+            orgId: ObjectId::new(),
+            teamId: ObjectId::new(),
+            credentialId: None,
+            name: model.to_string(),
+            model: model.to_string(),
+            embeddingLength: embedding_length,
+            modelType: "embedding".to_string(),
+        }
+    }
+
+    #[test]
+    fn model_overrides_take_precedence_over_the_configured_model() {
+        let overrides = HashMap::from([("ds".to_string(), "text-embedding-3-large".to_string())]);
+        let configured = configured_model("text-embedding-3-small", 1536);
+        let resolved = resolve_embedding_model("ds", &overrides, Some(configured)).unwrap();
+        assert_eq!(resolved, ("text-embedding-3-large".to_string(), 3072));
+
+        let overrides = HashMap::from([("ds".to_string(), "no-such-model".to_string())]);
+        let result = resolve_embedding_model("ds", &overrides, None);
+        assert!(matches!(result, Err(ProcessError::ModelNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn datasources_get_vectors_of_their_own_model() {
+        let overrides = HashMap::from([("ds_large".to_string(), "text-embedding-3-large".into())]);
+        let mut datasources = vec![];
+        for datasource_id in ["ds_small", "ds_large"] {
+            let configured = configured_model("text-embedding-3-small", 1536);
+            let (embedding_model_name, vector_length) =
+                resolve_embedding_model(datasource_id, &overrides, Some(configured)).unwrap();
+            let settings = IngestionSettings {
+                embedding_model_name,
+                vector_length,
+                ..test_settings(None, false)
+            };
+            // Both datasources embed the same text, the cache keeps their models apart
+            warm_embedding_cache(&settings, &["per model text"]).await;
+            datasources.push((datasource_id, settings));
+        }
+        let mongo_conn = unused_mongo().await;
+        for (datasource_id, settings) in datasources {
+            let records = to_records(json!([{"text": "per model text"}]));
+            let points = embed_records(&mongo_conn, datasource_id, records, &settings)
+                .await
+                .unwrap();
+            let Some(VectorsOptions::Vectors(vectors)) = points[0]
+                .vectors
+                .clone()
+                .and_then(|vectors| vectors.vectors_options)
+            else {
+                panic!("points should have named vectors");
+            };
+            let vector = &vectors.vectors[&settings.embedding_model_name];
+            assert_eq!(vector.data.len() as u64, settings.vector_length);
+        }
+    }
+
+    #[tokio::test]
+    async fn embedding_errors_name_the_record_that_failed() {
+        let mut settings = test_settings(None, false);
+        settings.options.embedding_timeout = Duration::from_millis(50);
+        settings.options.embedding_retry_policy = RetryPolicy::new(0, 1);
+        let texts = ["failing lamp", "failing desk"];
+        warm_embedding_cache(&settings, &texts).await;
+        // Only the third record misses the cache, its embedding request can't reach the model's
+        // credentials in Mongo and fails
+        let message = json!([{"text": texts[0]}, {"text": texts[1]}, {"text": "failing chair"}]);
+        let records = parse_message(&message.to_string(), MessageFormat::Json).await.unwrap();
+        let datasource_id = ObjectId::new().to_hex();
+        let result = embed_records(&unused_mongo().await, &datasource_id, records, &settings).await;
+        let Err(error @ ProcessError::RecordFailed { .. }) = result else {
+            panic!("expected the record to fail");
+        };
+        let ProcessError::RecordFailed { record, source } = &error else {
+            unreachable!();
+        };
+        assert!(matches!(
+            **source,
+            ProcessError::EmbeddingTimeout(_) | ProcessError::EmbeddingFailed(_)
+        ));
+        assert_eq!(record.source_index, Some(2));
+        assert!(error.to_string().starts_with("record 2 "));
+        assert!(record.preview.contains("text: failing chair"));
+    }
+
+    #[tokio::test]
+    async fn records_without_text_are_named_in_the_error() {
+        let settings = test_settings(None, false);
+        // The third record has no text to embed
+        let message = json!([
+            {"text": "snapshot lamp"},
+            {"text": "snapshot desk"},
+            {"title": "Chair"}
+        ]);
+        let records = parse_message(&message.to_string(), MessageFormat::Json).await.unwrap();
+        let result = embed_records(&unused_mongo().await, "ds", records, &settings).await;
+        let Err(error @ ProcessError::RecordFailed { .. }) = result else {
+            panic!("expected the record to fail");
+        };
+        let ProcessError::RecordFailed { record, .. } = &error else {
+            unreachable!();
+        };
+        assert_eq!(record.source_index, Some(2));
+        assert!(error.to_string().starts_with("record 2 "));
+        assert!(record.preview.contains("title: Chair"));
+    }
+
+    /// Upserts only return once `barrier` is full, so calls that are serialized never do
+    struct BarrierVectorStore {
+        barrier: tokio::sync::Barrier,
+        store: InMemoryVectorStore,
+    }
+
+    #[async_trait]
+    impl VectorStore for BarrierVectorStore {
+        fn datasource_id(&self) -> &str {
+            self.store.datasource_id()
+        }
+
+        async fn ensure_collection(&self, size: u64, vector_name: Option<String>) -> Result<()> {
+            self.store.ensure_collection(size, vector_name).await
+        }
+
+        async fn bulk_upsert(
+            &self,
+            points: Vec<PointStruct>,
+            vector_length: Option<u64>,
+            vector_name: Option<String>,
+        ) -> Result<UpsertReport> {
+            self.barrier.wait().await;
+            self.store.bulk_upsert(points, vector_length, vector_name).await
+        }
+
+        async fn delete_by_datasource(&self) -> Result<u64> {
+            self.store.delete_by_datasource().await
+        }
+
+        async fn point_ids(&self) -> Result<Vec<PointId>> {
+            self.store.point_ids().await
+        }
+
+        async fn delete_points(&self, ids: Vec<PointId>) -> Result<()> {
+            self.store.delete_points(ids).await
+        }
+    }
+
+    #[tokio::test]
+    async fn simultaneous_messages_do_not_block_each_other() {
+        let settings = test_settings(None, false);
+        let texts = ["concurrent lamp", "concurrent desk"];
+        warm_embedding_cache(&settings, &texts).await;
+        let vector_store = Arc::new(BarrierVectorStore {
+            barrier: tokio::sync::Barrier::new(2),
+            store: InMemoryVectorStore::default(),
+        });
+        let mongo_conn = unused_mongo().await;
+        let gate = MessageGate::new(2);
+        let ingest = |text: &str| {
+            let incoming = IncomingMessage {
+                message: json!({"text": text}).to_string(),
+                message_format: MessageFormat::Json,
+                datasource_id: "ds".to_string(),
+                message_id: None,
+            };
+            process_messages_with(
+                vector_store.clone(),
+                &mongo_conn,
+                incoming,
+                Arc::new(InMemoryIdempotencyStore::default()),
+                &gate,
+                |_| async { Ok(test_settings(None, false)) },
+            )
+        };
+        // Each upsert waits for the other one, so this only finishes if both run at once
+        let (first, second) = tokio::time::timeout(
+            Duration::from_secs(5),
+            async { tokio::join!(ingest(texts[0]), ingest(texts[1])) },
+        )
+            .await
+            .expect("the two messages should be ingested concurrently");
+        assert_eq!(first.unwrap().succeeded, 1);
+        assert_eq!(second.unwrap().succeeded, 1);
+        assert_eq!(vector_store.store.points.lock().unwrap().len(), 2);
+    }
+
+    /// Records how many upserts are in flight at once, each one taking a while
+    #[derive(Default)]
+    struct PeakVectorStore {
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+        store: InMemoryVectorStore,
+    }
+
+    #[async_trait]
+    impl VectorStore for PeakVectorStore {
+        fn datasource_id(&self) -> &str {
+            self.store.datasource_id()
+        }
+
+        async fn ensure_collection(&self, size: u64, vector_name: Option<String>) -> Result<()> {
+            self.store.ensure_collection(size, vector_name).await
+        }
+
+        async fn bulk_upsert(
+            &self,
+            points: Vec<PointStruct>,
+            vector_length: Option<u64>,
+            vector_name: Option<String>,
+        ) -> Result<UpsertReport> {
+            let current = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.store.bulk_upsert(points, vector_length, vector_name).await
+        }
+
+        async fn delete_by_datasource(&self) -> Result<u64> {
+            self.store.delete_by_datasource().await
+        }
+
+        async fn point_ids(&self) -> Result<Vec<PointId>> {
+            self.store.point_ids().await
+        }
+
+        async fn delete_points(&self, ids: Vec<PointId>) -> Result<()> {
+            self.store.delete_points(ids).await
+        }
+    }
+
+    #[tokio::test]
+    async fn at_most_max_concurrent_messages_are_ingested_at_once() {
+        let settings = test_settings(None, false);
+        let texts = ["gated lamp", "gated desk", "gated chair", "gated shelf", "gated rug"];
+        warm_embedding_cache(&settings, &texts).await;
+        let vector_store = Arc::new(PeakVectorStore::default());
+        let mongo_conn = unused_mongo().await;
+        let gate = MessageGate::new(2);
+        let ingestions = texts.iter().map(|text| {
+            let incoming = IncomingMessage {
+                message: json!({"text": text}).to_string(),
+                message_format: MessageFormat::Json,
+                datasource_id: "ds".to_string(),
+                message_id: None,
+            };
+            process_messages_with(
+                vector_store.clone(),
+                &mongo_conn,
+                incoming,
+                Arc::new(InMemoryIdempotencyStore::default()),
+                &gate,
+                |_| async { Ok(test_settings(None, false)) },
+            )
+        });
+        let reports = futures::future::join_all(ingestions).await;
+        assert!(reports.iter().all(|report| report.as_ref().unwrap().succeeded == 1));
+        assert_eq!(vector_store.peak.load(Ordering::SeqCst), 2);
+        assert_eq!(vector_store.store.points.lock().unwrap().len(), texts.len());
+    }
 }
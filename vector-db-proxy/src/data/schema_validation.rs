@@ -0,0 +1,135 @@
+use serde_json::{Map, Value};
+
+use crate::mongo::models::{JsonType, RecordSchema};
+
+fn json_type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn json_type_name(json_type: JsonType) -> &'static str {
+    match json_type {
+        JsonType::String => "string",
+        JsonType::Number => "number",
+        JsonType::Integer => "integer",
+        JsonType::Boolean => "boolean",
+        JsonType::Object => "object",
+        JsonType::Array => "array",
+    }
+}
+
+fn matches_type(value: &Value, expected: JsonType) -> bool {
+    match expected {
+        JsonType::String => value.is_string(),
+        // Integers are numbers too
+        JsonType::Number => value.is_number(),
+        JsonType::Integer => value.is_i64() || value.is_u64(),
+        JsonType::Boolean => value.is_boolean(),
+        JsonType::Object => value.is_object(),
+        JsonType::Array => value.is_array(),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `record`: A record from an incoming message, before it is flattened for embedding
+/// * `schema`: The datasource's required fields and their types
+///
+/// returns: Result<(), Vec<String>> one description per offending field, e.g.
+/// `title: missing` or `price: expected number, found string`. Null counts as missing
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn validate_record(
+    record: &Map<String, Value>,
+    schema: &RecordSchema,
+) -> Result<(), Vec<String>> {
+    let mut violations: Vec<String> = schema
+        .requiredFields
+        .iter()
+        .filter_map(|(field, expected)| match record.get(field) {
+            None | Some(Value::Null) => Some(format!("{}: missing", field)),
+            Some(value) if !matches_type(value, *expected) => Some(format!(
+                "{}: expected {}, found {}",
+                field,
+                json_type_name(*expected),
+                json_type_of(value)
+            )),
+            Some(_) => None,
+        })
+        .collect();
+    if violations.is_empty() {
+        return Ok(());
+    }
+    // HashMap iteration order is random, keep the messages stable
+    violations.sort();
+    Err(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn schema() -> RecordSchema {
+        RecordSchema {
+            requiredFields: HashMap::from([
+                ("title".to_string(), JsonType::String),
+                ("price".to_string(), JsonType::Number),
+            ]),
+        }
+    }
+
+    fn to_record(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn records_matching_the_schema_are_valid() {
+        let record = to_record(json!({"title": "Lamp", "price": 12.5, "colour": "red"}));
+        assert_eq!(validate_record(&record, &schema()), Ok(()));
+        // Integers are numbers too
+        let record = to_record(json!({"title": "Lamp", "price": 12}));
+        assert_eq!(validate_record(&record, &schema()), Ok(()));
+    }
+
+    #[test]
+    fn missing_required_fields_are_reported() {
+        let record = to_record(json!({"price": 12.5}));
+        assert_eq!(
+            validate_record(&record, &schema()),
+            Err(vec!["title: missing".to_string()])
+        );
+        // Null counts as missing
+        let record = to_record(json!({"title": null, "price": 12.5}));
+        assert_eq!(
+            validate_record(&record, &schema()),
+            Err(vec!["title: missing".to_string()])
+        );
+    }
+
+    #[test]
+    fn type_mismatches_are_reported() {
+        let record = to_record(json!({"title": 7, "price": "12.50"}));
+        assert_eq!(
+            validate_record(&record, &schema()),
+            Err(vec![
+                "price: expected number, found string".to_string(),
+                "title: expected string, found integer".to_string(),
+            ])
+        );
+    }
+}
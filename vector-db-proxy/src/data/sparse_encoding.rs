@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+/// BM25 term frequency saturation
+const K1: f32 = 1.2;
+/// BM25 document length normalisation
+const B: f32 = 0.75;
+/// Document length (in tokens) that chunks are normalised against. Chunks are capped by the
+/// splitter so a fixed estimate stands in for the corpus average BM25 would normally use
+const AVERAGE_DOCUMENT_LENGTH: f32 = 256.0;
+
+/// Sparse vector in the form Qdrant expects: sorted, unique dimension indices and their weights
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SparseVector {
+    pub indices: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+/// Lowercased alphanumeric runs of the text
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is guaranteed to give the same index for a token across
+/// builds. Points and queries must agree on it
+fn token_index(token: &str) -> u32 {
+    token.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn term_frequencies(text: &str) -> (BTreeMap<u32, f32>, usize) {
+    let mut frequencies: BTreeMap<u32, f32> = BTreeMap::new();
+    let mut length = 0;
+    for token in tokenize(text) {
+        *frequencies.entry(token_index(&token)).or_default() += 1.0;
+        length += 1;
+    }
+    (frequencies, length)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `text`: Document (chunk) text to encode
+///
+/// Weighs each term with BM25's saturated term frequency. There is no IDF component since the
+/// corpus isn't known up front, rare terms still rank highly because fewer points contain them
+///
+/// returns: SparseVector
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn encode_document(text: &str) -> SparseVector {
+    let (frequencies, length) = term_frequencies(text);
+    let length_norm = 1.0 - B + B * length as f32 / AVERAGE_DOCUMENT_LENGTH;
+    let (indices, values) = frequencies
+        .into_iter()
+        .map(|(index, tf)| (index, tf * (K1 + 1.0) / (tf + K1 * length_norm)))
+        .unzip();
+    SparseVector { indices, values }
+}
+
+/// Encodes query text, every distinct term gets a weight of 1 so that the score is the sum of
+/// the matching terms' document weights
+pub fn encode_query(text: &str) -> SparseVector {
+    let (frequencies, _) = term_frequencies(text);
+    let (indices, values) = frequencies.into_keys().map(|index| (index, 1.0)).unzip();
+    SparseVector { indices, values }
+}
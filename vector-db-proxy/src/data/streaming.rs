@@ -0,0 +1,125 @@
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Read;
+use tokio::sync::mpsc::Sender;
+
+use crate::data::models::{stamp_source_index, InvalidRecordPolicy};
+
+/// Sends every JSON object of a message down `sender` as soon as it has been parsed
+struct RecordSender<'a> {
+    sender: &'a Sender<Map<String, Value>>,
+    on_invalid_record: InvalidRecordPolicy,
+}
+
+impl<'a> RecordSender<'a> {
+    fn send<E: de::Error>(&self, record: Map<String, Value>) -> Result<(), E> {
+        // Blocks when the channel is full, which is what keeps memory flat when the downstream
+        // stages fall behind
+        self.sender
+            .blocking_send(record)
+            .map_err(|_| E::custom("record receiver was dropped"))
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for RecordSender<'a> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON object or an array of JSON objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        let mut index = 0;
+        while let Some(element) = seq.next_element::<Value>()? {
+            let record = self
+                .on_invalid_record
+                .to_record(index, element)
+                .map_err(de::Error::custom)?;
+            if let Some(mut record) = record {
+                stamp_source_index(&mut record, index);
+                self.send(record)?;
+                count += 1;
+            }
+            index += 1;
+        }
+        Ok(count)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<usize, A::Error> {
+        let mut record = Map::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        stamp_source_index(&mut record, 0);
+        self.send(record)?;
+        Ok(1)
+    }
+
+    // Scalars at the top level hold no records
+    fn visit_unit<E: de::Error>(self) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<usize, E> {
+        Ok(0)
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<usize, E> {
+        Ok(0)
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for RecordSender<'a> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `reader`: Source of a message holding a single JSON object or an array of JSON objects
+/// * `sender`: Bounded channel the records are sent down one at a time, so that only the records
+/// in flight are ever held in memory rather than the whole message
+/// * `on_invalid_record`: How array elements that aren't objects are handled. With
+/// `InvalidRecordPolicy::Fail` parsing stops with an error naming the element
+///
+/// Blocks the calling thread, so it should be run with `spawn_blocking`.
+///
+/// returns: Result<usize, Error> the number of records sent. Parsing stops with an error if the
+/// receiver is dropped
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn stream_json_records<R: Read>(
+    reader: R,
+    sender: Sender<Map<String, Value>>,
+    on_invalid_record: InvalidRecordPolicy,
+) -> Result<usize, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let record_sender = RecordSender {
+        sender: &sender,
+        on_invalid_record,
+    };
+    let count = record_sender.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(count)
+}
@@ -1,14 +1,22 @@
 use crate::data::models::Document;
 use crate::data::utils::{cosine_similarity, percentile};
 use crate::llm::utils::embed_text;
-use crate::llm::{models::EmbeddingModels, utils::embed_text_chunks_async};
+use crate::llm::{
+    models::{EmbeddingModels, FastEmbedModels},
+    utils::embed_text_chunks_async,
+};
 use crate::mongo::models::ChunkingStrategy;
 use anyhow::{anyhow, Result};
 use ndarray::Array1;
 use std::collections::HashMap;
-use std::sync::{Arc};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use mongodb::Database;
+use once_cell::sync::Lazy;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+use tokenizers::Tokenizer;
 use tokio::sync::RwLock;
+use tracing::warn;
 
 // `Sentence` is a struct that holds the embedding and other metadata
 #[derive(Clone, Debug)]
@@ -284,3 +292,187 @@ impl Chunker {
         }
     }
 }
+
+/// Where fastembed's `InitOptions::default()` downloads its models
+const FASTEMBED_CACHE_DIR: &str = "local_cache";
+
+/// A UTF-8 character is at most four bytes, so a window that ends inside one decodes once it is
+/// shortened by at most three tokens
+const MAX_BOUNDARY_BACKOFF: usize = 3;
+
+static CL100K: Lazy<CoreBPE> =
+    Lazy::new(|| cl100k_base().expect("cl100k_base ranks are bundled with tiktoken-rs"));
+
+// Tokenizers are loaded once per model rather than once per message
+static HF_TOKENIZERS: Lazy<Mutex<HashMap<String, Arc<Tokenizer>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The tokenizer that text is counted and split with before it is embedded, matching the one
+/// the embedding model itself uses
+#[derive(Clone)]
+pub enum ChunkTokenizer {
+    /// cl100k_base, as used by OpenAI's embedding models
+    Cl100k,
+    /// The Hugging Face tokenizer fastembed ships with the model
+    HuggingFace(Arc<Tokenizer>),
+}
+
+impl ChunkTokenizer {
+    /// Picks the tokenizer of the datasource's embedding model. fastembed models use the
+    /// `tokenizer.json` downloaded alongside the model, until the model has been downloaded
+    /// their tokens are approximated with cl100k_base.
+    pub fn for_model(model: EmbeddingModels) -> Self {
+        let model_name = model.to_str().unwrap_or_default().to_string();
+        let Some(fastembed_model) = FastEmbedModels::from(model_name).translate() else {
+            return ChunkTokenizer::Cl100k;
+        };
+        let model_name = fastembed_model.to_string();
+        let mut tokenizers = HF_TOKENIZERS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tokenizer) = tokenizers.get(&model_name) {
+            return ChunkTokenizer::HuggingFace(tokenizer.clone());
+        }
+        let path = Path::new(FASTEMBED_CACHE_DIR).join(&model_name).join("tokenizer.json");
+        match Tokenizer::from_file(&path) {
+            Ok(mut tokenizer) => {
+                // The file truncates to the model's context, which would drop the text past it
+                tokenizer.with_padding(None);
+                if let Err(e) = tokenizer.with_truncation(None) {
+                    warn!(model = %model_name, error = %e, "Couldn't disable tokenizer truncation");
+                    return ChunkTokenizer::Cl100k;
+                }
+                let tokenizer = Arc::new(tokenizer);
+                tokenizers.insert(model_name, tokenizer.clone());
+                ChunkTokenizer::HuggingFace(tokenizer)
+            }
+            Err(e) => {
+                warn!(
+                    model = %model_name,
+                    error = %e,
+                    "Model tokenizer not available yet, approximating tokens with cl100k_base"
+                );
+                ChunkTokenizer::Cl100k
+            }
+        }
+    }
+}
+
+/// Splits text into windows of at most `max_tokens` tokens of `tokenizer`, each window repeating
+/// the last `overlap` tokens of the previous one. Text that already fits is returned as a
+/// single chunk. Window boundaries never fall inside a character, a window that would end in
+/// one is shortened instead.
+pub fn split_text_by_tokens(
+    tokenizer: &ChunkTokenizer,
+    text: &str,
+    max_tokens: usize,
+    overlap: usize,
+) -> Vec<String> {
+    match tokenizer {
+        ChunkTokenizer::Cl100k => {
+            let tokens = CL100K.encode_ordinary(text);
+            if max_tokens == 0 || tokens.len() <= max_tokens {
+                return vec![text.to_string()];
+            }
+            split_windows(tokens.len(), max_tokens, overlap, |start, end| {
+                CL100K.decode(tokens[start..end].to_vec()).ok()
+            })
+        }
+        ChunkTokenizer::HuggingFace(hf_tokenizer) => {
+            let offsets = match hf_tokenizer.encode(text, false) {
+                Ok(encoding) => encoding.get_offsets().to_vec(),
+                Err(e) => {
+                    warn!(error = %e, "Tokenizing text failed, falling back to cl100k_base");
+                    return split_text_by_tokens(&ChunkTokenizer::Cl100k, text, max_tokens, overlap);
+                }
+            };
+            if max_tokens == 0 || offsets.len() <= max_tokens {
+                return vec![text.to_string()];
+            }
+            // Offsets index into the original text, so each window is a slice of it
+            split_windows(offsets.len(), max_tokens, overlap, |start, end| {
+                text.get(offsets[start].0..offsets[end - 1].1).map(str::to_string)
+            })
+        }
+    }
+}
+
+/// Walks `token_count` tokens in windows of `max_tokens`, asking `chunk_at` for the text of each
+/// window. When a window doesn't decode its end is moved back one token at a time, and a window
+/// whose start is inside a character is moved forward.
+fn split_windows(
+    token_count: usize,
+    max_tokens: usize,
+    overlap: usize,
+    mut chunk_at: impl FnMut(usize, usize) -> Option<String>,
+) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < token_count {
+        let window_end = (start + max_tokens).min(token_count);
+        let shortest_end = window_end.saturating_sub(MAX_BOUNDARY_BACKOFF).max(start + 1);
+        let chunk = (shortest_end..=window_end)
+            .rev()
+            .find_map(|end| chunk_at(start, end).map(|chunk| (chunk, end)));
+        match chunk {
+            Some((chunk, end)) => {
+                chunks.push(chunk);
+                if end == token_count {
+                    break;
+                }
+                start = end.saturating_sub(overlap).max(start + 1);
+            }
+            None => start += 1,
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: [&str; 10] =
+        ["one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten"];
+
+    // Each of these words is a single cl100k_base token, with or without its leading space
+    fn word_tokens(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| match i {
+                0 => WORDS[0].to_string(),
+                _ => format!(" {}", WORDS[i % WORDS.len()]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn long_text_is_split_into_overlapping_chunks() {
+        let tokens = word_tokens(24);
+        let text = tokens.concat();
+        assert_eq!(CL100K.encode_ordinary(&text).len(), 24);
+
+        let chunks = split_text_by_tokens(&ChunkTokenizer::Cl100k, &text, 10, 3);
+
+        // Windows of 10 tokens starting every 7 tokens: 0..10, 7..17 and 14..24
+        assert_eq!(
+            chunks,
+            vec![tokens[0..10].concat(), tokens[7..17].concat(), tokens[14..24].concat()]
+        );
+    }
+
+    #[test]
+    fn text_that_fits_is_a_single_chunk() {
+        let text = word_tokens(10).concat();
+        assert_eq!(split_text_by_tokens(&ChunkTokenizer::Cl100k, &text, 10, 3), vec![text]);
+    }
+
+    #[test]
+    fn chunks_never_split_a_character() {
+        let text = "héllo wörld 日本語のテキスト 🎉🎉🎉 ".repeat(20);
+
+        let chunks = split_text_by_tokens(&ChunkTokenizer::Cl100k, &text, 4, 0);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| !chunk.contains('\u{FFFD}')));
+        // Without overlap the chunks are the text cut at character boundaries
+        assert_eq!(chunks.concat(), text);
+    }
+}
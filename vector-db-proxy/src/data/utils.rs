@@ -37,7 +37,7 @@ pub async fn extract_text_from_file(
     document_name: String,
     datasource_id: String,
     queue: Arc<RwLock<MyQueue<String>>>,
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    qdrant_conn: Arc<QdrantClient>,
     mongo_conn: Arc<RwLock<Database>>,
     // redis_conn_pool: Arc<Mutex<RedisConnection>>,
 ) -> Option<(String, Option<HashMap<String, String>>)> {
@@ -0,0 +1,2 @@
+pub mod mongo_store;
+pub mod traits;
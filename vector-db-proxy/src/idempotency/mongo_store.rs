@@ -0,0 +1,33 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mongodb::Database;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::idempotency::traits::IdempotencyStore;
+use crate::mongo::queries::{is_message_processed, mark_message_processed};
+
+/// Keeps processed message keys in Mongo so they survive restarts, which is when redeliveries
+/// usually happen
+pub struct MongoIdempotencyStore {
+    mongo_conn: Arc<RwLock<Database>>,
+}
+
+impl MongoIdempotencyStore {
+    pub fn new(mongo_conn: Arc<RwLock<Database>>) -> Self {
+        MongoIdempotencyStore { mongo_conn }
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MongoIdempotencyStore {
+    async fn is_processed(&self, key: &str) -> Result<bool> {
+        let mongodb_connection = self.mongo_conn.read().await;
+        is_message_processed(&mongodb_connection, key).await
+    }
+
+    async fn mark_processed(&self, key: &str) -> Result<()> {
+        let mongodb_connection = self.mongo_conn.read().await;
+        mark_message_processed(&mongodb_connection, key).await
+    }
+}
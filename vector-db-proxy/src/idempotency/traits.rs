@@ -0,0 +1,14 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// Remembers which messages have already been ingested so that a redelivered message isn't
+/// embedded and upserted a second time. Keys are only recorded once a message was fully
+/// processed, so a message that failed part way through is processed again on redelivery.
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    /// Whether the message with this key has already been processed
+    async fn is_processed(&self, key: &str) -> Result<bool>;
+
+    /// Records that the message with this key was processed successfully
+    async fn mark_processed(&self, key: &str) -> Result<()>;
+}
@@ -11,8 +11,40 @@ pub struct GlobalData {
     pub rabbitmq_routing_key: String,
     pub rabbitmq_username: String,
     pub rabbitmq_password: String,
+    pub rabbitmq_prefetch_count: u16,
+    pub rabbitmq_max_redeliveries: u32,
+    pub rabbitmq_dead_letter_queue: String,
+    pub rabbitmq_retry_base_delay_ms: u64,
     pub mongo_uri: String,
     pub qdrant_uri: String,
+    pub qdrant_tls: bool,
+    pub qdrant_rest_uri: String,
+    pub qdrant_snapshots_path: String,
+    pub qdrant_collection_per_tenant: bool,
+    pub qdrant_max_retries: u32,
+    pub qdrant_base_delay_ms: u64,
+    pub qdrant_upsert_batch_size: usize,
+    pub qdrant_distance: String,
+    pub qdrant_payload_indexes: String,
+    pub embedding_model_overrides: String,
+    pub embedding_cache_size: usize,
+    pub embedding_requests_per_minute: u32,
+    pub embedding_tokens_per_minute: u32,
+    pub embedding_max_tokens: usize,
+    pub embedding_chunk_overlap: usize,
+    pub embedding_timeout_secs: u64,
+    pub embedding_max_retries: u32,
+    pub embedding_retry_base_delay_ms: u64,
+    pub embedding_normalize: bool,
+    pub embedding_breaker_threshold: u32,
+    pub embedding_breaker_cooldown_secs: u64,
+    pub text_preprocessing: String,
+    pub max_payload_bytes: usize,
+    pub oversize_behavior: String,
+    pub max_concurrent_embeddings: usize,
+    pub max_concurrent_messages: usize,
+    pub on_invalid_record: String,
+    pub shutdown_grace_period_secs: u64,
     pub webapp_host: String,
     pub webapp_port: String,
     pub redis_host: String,
@@ -27,7 +59,7 @@ impl GlobalData {
             host: dotenv::var("HOST").unwrap_or("0.0.0.0".to_string()),
             port: dotenv::var("PORT").unwrap_or("9001".to_string()),
             rabbitmq_port: dotenv::var("RABBITMQ_PORT")
-                .unwrap()
+                .unwrap_or("5672".to_string())
                 .parse()
                 .unwrap_or(5672),
             rabbitmq_host: dotenv::var("RABBITMQ_HOST").unwrap_or("localhost".to_string()),
@@ -36,8 +68,40 @@ impl GlobalData {
             rabbitmq_routing_key: dotenv::var("RABBITMQ_ROUTING_KEY").unwrap_or("key".to_string()),
             rabbitmq_username: dotenv::var("RABBITMQ_USERNAME").unwrap_or("guest".to_string()),
             rabbitmq_password: dotenv::var("RABBITMQ_PASSWORD").unwrap_or("guest".to_string()),
+            rabbitmq_prefetch_count: dotenv::var("RABBITMQ_PREFETCH_COUNT").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            rabbitmq_max_redeliveries: dotenv::var("RABBITMQ_MAX_REDELIVERIES").unwrap_or("3".to_string()).parse().unwrap_or(3),
+            rabbitmq_dead_letter_queue: dotenv::var("RABBITMQ_DEAD_LETTER_QUEUE").unwrap_or("dead_letter".to_string()),
+            rabbitmq_retry_base_delay_ms: dotenv::var("RABBITMQ_RETRY_BASE_DELAY_MS").unwrap_or("1000".to_string()).parse().unwrap_or(1000),
             mongo_uri: dotenv::var("MONGO_URI").unwrap_or("mongodb://localhost:27017".to_string()),
             qdrant_uri: dotenv::var("QDRANT_URI").unwrap_or("htttp://localhost:6334".to_string()),
+            qdrant_tls: dotenv::var("QDRANT_TLS").unwrap_or("false".to_string()).parse().unwrap_or(false),
+            qdrant_rest_uri: dotenv::var("QDRANT_REST_URI").unwrap_or("http://localhost:6333".to_string()),
+            qdrant_snapshots_path: dotenv::var("QDRANT_SNAPSHOTS_PATH").unwrap_or("/qdrant/snapshots".to_string()),
+            qdrant_collection_per_tenant: dotenv::var("QDRANT_COLLECTION_PER_TENANT").unwrap_or("false".to_string()).parse().unwrap_or(false),
+            qdrant_max_retries: dotenv::var("QDRANT_MAX_RETRIES").unwrap_or("3".to_string()).parse().unwrap_or(3),
+            qdrant_base_delay_ms: dotenv::var("QDRANT_BASE_DELAY_MS").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            qdrant_upsert_batch_size: dotenv::var("QDRANT_UPSERT_BATCH_SIZE").unwrap_or("256".to_string()).parse().unwrap_or(256),
+            qdrant_distance: dotenv::var("QDRANT_DISTANCE").unwrap_or("cosine".to_string()),
+            qdrant_payload_indexes: dotenv::var("QDRANT_PAYLOAD_INDEXES").unwrap_or("".to_string()),
+            embedding_model_overrides: dotenv::var("EMBEDDING_MODEL_OVERRIDES").unwrap_or("".to_string()),
+            embedding_cache_size: dotenv::var("EMBEDDING_CACHE_SIZE").unwrap_or("1000".to_string()).parse().unwrap_or(1000),
+            embedding_requests_per_minute: dotenv::var("EMBEDDING_REQUESTS_PER_MINUTE").unwrap_or("0".to_string()).parse().unwrap_or(0),
+            embedding_tokens_per_minute: dotenv::var("EMBEDDING_TOKENS_PER_MINUTE").unwrap_or("0".to_string()).parse().unwrap_or(0),
+            embedding_max_tokens: dotenv::var("EMBEDDING_MAX_TOKENS").unwrap_or("8191".to_string()).parse().unwrap_or(8191),
+            embedding_chunk_overlap: dotenv::var("EMBEDDING_CHUNK_OVERLAP").unwrap_or("100".to_string()).parse().unwrap_or(100),
+            embedding_timeout_secs: dotenv::var("EMBEDDING_TIMEOUT_SECS").unwrap_or("30".to_string()).parse().unwrap_or(30),
+            embedding_max_retries: dotenv::var("EMBEDDING_MAX_RETRIES").unwrap_or("3".to_string()).parse().unwrap_or(3),
+            embedding_retry_base_delay_ms: dotenv::var("EMBEDDING_RETRY_BASE_DELAY_MS").unwrap_or("200".to_string()).parse().unwrap_or(200),
+            embedding_normalize: dotenv::var("EMBEDDING_NORMALIZE").unwrap_or("false".to_string()).parse().unwrap_or(false),
+            embedding_breaker_threshold: dotenv::var("EMBEDDING_BREAKER_THRESHOLD").unwrap_or("5".to_string()).parse().unwrap_or(5),
+            embedding_breaker_cooldown_secs: dotenv::var("EMBEDDING_BREAKER_COOLDOWN_SECS").unwrap_or("30".to_string()).parse().unwrap_or(30),
+            text_preprocessing: dotenv::var("TEXT_PREPROCESSING").unwrap_or("".to_string()),
+            max_payload_bytes: dotenv::var("MAX_PAYLOAD_BYTES").unwrap_or("1048576".to_string()).parse().unwrap_or(1048576),
+            oversize_behavior: dotenv::var("OVERSIZE_BEHAVIOR").unwrap_or("truncate".to_string()),
+            max_concurrent_embeddings: dotenv::var("MAX_CONCURRENT_EMBEDDINGS").unwrap_or("8".to_string()).parse().unwrap_or(8),
+            max_concurrent_messages: dotenv::var("MAX_CONCURRENT_MESSAGES").unwrap_or("4".to_string()).parse().unwrap_or(4),
+            on_invalid_record: dotenv::var("ON_INVALID_RECORD").unwrap_or("skip".to_string()),
+            shutdown_grace_period_secs: dotenv::var("SHUTDOWN_GRACE_PERIOD_SECS").unwrap_or("30".to_string()).parse().unwrap_or(30),
             webapp_host: dotenv::var("WEBAPP_HOST").unwrap_or("localhost".to_string()),
             webapp_port: dotenv::var("WEBAPP_PORT").unwrap_or("3000".to_string()),
             redis_host: dotenv::var("REDIS_HOST").unwrap_or("localhost".to_string()),
@@ -0,0 +1,62 @@
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use tokio::sync::OnceCell;
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+/// An LRU cache of embeddings keyed on the embedded text and the name of the model that embedded
+/// it. A capacity of 0 disables the cache entirely.
+pub struct EmbeddingCache {
+    cache: Option<Mutex<LruCache<u64, Vec<f32>>>>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize) -> Self {
+        EmbeddingCache {
+            cache: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+        }
+    }
+
+    fn key(model_name: &str, text: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        model_name.hash(&mut hasher);
+        text.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    pub fn get(&self, model_name: &str, text: &str) -> Option<Vec<f32>> {
+        let cache = self.cache.as_ref()?;
+        let key = Self::key(model_name, text);
+        match cache.lock() {
+            Ok(mut cache) => cache.get(&key).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn insert(&self, model_name: &str, text: &str, embedding: Vec<f32>) {
+        if let Some(cache) = self.cache.as_ref() {
+            if let Ok(mut cache) = cache.lock() {
+                cache.put(Self::key(model_name, text), embedding);
+            }
+        }
+    }
+}
+
+static EMBEDDING_CACHE: OnceCell<EmbeddingCache> = OnceCell::const_new();
+
+/// The embedding cache shared by every ingestion, sized by `embedding_cache_size`
+pub async fn embedding_cache() -> &'static EmbeddingCache {
+    EMBEDDING_CACHE
+        .get_or_init(|| async {
+            let global_data = GLOBAL_DATA.read().await;
+            EmbeddingCache::new(global_data.embedding_cache_size)
+        })
+        .await
+}
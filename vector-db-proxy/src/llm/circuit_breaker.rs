@@ -0,0 +1,215 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    /// Calls are rejected until the cooldown ends
+    Open { until: Instant },
+    /// The cooldown is over and a single probe call is in flight to test the backend
+    HalfOpen,
+}
+
+/// Stops calling a backend that keeps failing. After `failure_threshold` consecutive failures the
+/// breaker opens and rejects calls for `cooldown`, then lets one probe through: if it succeeds
+/// the breaker closes again, if it fails the breaker re-opens for another cooldown. A threshold
+/// of 0 disables the breaker.
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+/// An allowed call. Report how it went with `succeeded` or `failed`; a probe dropped without
+/// either (e.g. its future was cancelled) counts as failed so the breaker never stays half open
+pub struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl BreakerPermit<'_> {
+    pub fn succeeded(mut self) {
+        self.probe = false;
+        self.breaker.record_success();
+    }
+
+    pub fn failed(mut self) {
+        self.probe = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            state: Mutex::new(BreakerState::Closed {
+                consecutive_failures: 0,
+            }),
+            failure_threshold,
+            cooldown,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, BreakerState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Whether a call may go ahead, None while the breaker is open or a probe is in flight
+    pub fn allow(&self) -> Option<BreakerPermit<'_>> {
+        if self.failure_threshold == 0 {
+            return Some(BreakerPermit {
+                breaker: self,
+                probe: false,
+            });
+        }
+        let mut state = self.state();
+        let probe = match *state {
+            BreakerState::Closed { .. } => false,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => return None,
+        };
+        Some(BreakerPermit {
+            breaker: self,
+            probe,
+        })
+    }
+
+    fn record_success(&self) {
+        *self.state() = BreakerState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state();
+        *state = match *state {
+            BreakerState::Closed {
+                consecutive_failures,
+            } if consecutive_failures + 1 < self.failure_threshold => BreakerState::Closed {
+                consecutive_failures: consecutive_failures + 1,
+            },
+            // Tripped, or the half open probe failed
+            _ => BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            },
+        };
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(*self.state(), BreakerState::Open { until } if Instant::now() < until)
+    }
+}
+
+static EMBEDDING_CIRCUIT_BREAKER: OnceCell<CircuitBreaker> = OnceCell::const_new();
+
+/// The circuit breaker shared by every call to the embedding backend
+pub async fn embedding_circuit_breaker() -> &'static CircuitBreaker {
+    EMBEDDING_CIRCUIT_BREAKER
+        .get_or_init(|| async {
+            let global_data = GLOBAL_DATA.read().await;
+            CircuitBreaker::new(
+                global_data.embedding_breaker_threshold,
+                Duration::from_secs(global_data.embedding_breaker_cooldown_secs),
+            )
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    fn tripped_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..3 {
+            breaker.allow().unwrap().failed();
+        }
+        breaker
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trips_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.allow().unwrap().failed();
+        breaker.allow().unwrap().failed();
+        assert!(!breaker.is_open());
+        breaker.allow().unwrap().failed();
+        assert!(breaker.is_open());
+        assert!(breaker.allow().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.allow().unwrap().failed();
+        breaker.allow().unwrap().failed();
+        breaker.allow().unwrap().succeeded();
+        breaker.allow().unwrap().failed();
+        breaker.allow().unwrap().failed();
+        assert!(!breaker.is_open());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_a_single_probe_through_after_the_cooldown() {
+        let breaker = tripped_breaker();
+        tokio::time::advance(COOLDOWN).await;
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        probe.succeeded();
+        assert!(breaker.allow().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_reopens_the_breaker() {
+        let breaker = tripped_breaker();
+        tokio::time::advance(COOLDOWN).await;
+        breaker.allow().unwrap().failed();
+        assert!(breaker.is_open());
+        tokio::time::advance(COOLDOWN - Duration::from_secs(1)).await;
+        assert!(breaker.allow().is_none());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breaker.allow().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dropped_probe_does_not_leave_the_breaker_half_open() {
+        let breaker = tripped_breaker();
+        tokio::time::advance(COOLDOWN).await;
+        drop(breaker.allow().unwrap());
+        assert!(breaker.is_open());
+        tokio::time::advance(COOLDOWN).await;
+        assert!(breaker.allow().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_zero_threshold_never_trips() {
+        let breaker = CircuitBreaker::new(0, COOLDOWN);
+        for _ in 0..10 {
+            breaker.allow().unwrap().failed();
+        }
+        assert!(!breaker.is_open());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod utils;
-pub mod models;
\ No newline at end of file
+pub mod models;
+pub mod cache;
+pub mod rate_limiter;
+pub mod circuit_breaker;
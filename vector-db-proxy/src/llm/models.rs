@@ -46,6 +46,39 @@ impl EmbeddingModels {
             EmbeddingModels::UNKNOWN => None,
         }
     }
+
+    /// Length of the vectors the model produces
+    pub fn dimensions(&self) -> Option<u64> {
+        match self {
+            EmbeddingModels::OAI_ADA => Some(1536),
+            EmbeddingModels::OAI_SMALL => Some(1536),
+            EmbeddingModels::OAI_LARGE => Some(3072),
+            EmbeddingModels::BAAI_BGE_SMALL_EN => Some(384),
+            EmbeddingModels::BAAI_BGE_SMALL_EN_V1_5 => Some(384),
+            EmbeddingModels::BAAI_BGE_BASE_EN => Some(768),
+            EmbeddingModels::BAAI_BGE_BASE_EN_V1_5 => Some(768),
+            EmbeddingModels::ENTENCE_TRANSFORMERS_ALL_MINILM_L6_V2 => Some(384),
+            EmbeddingModels::XENOVA_FAST_MULTILINGUAL_E5_LARGE => Some(1024),
+            EmbeddingModels::UNKNOWN => None,
+        }
+    }
+
+    /// Most tokens the model embeds, text past it has to be split into chunks first. fastembed
+    /// truncates its input to 512 tokens, two of which are the start and end markers.
+    pub fn max_input_tokens(&self) -> usize {
+        match self {
+            EmbeddingModels::OAI_ADA
+            | EmbeddingModels::OAI_SMALL
+            | EmbeddingModels::OAI_LARGE
+            | EmbeddingModels::UNKNOWN => 8191,
+            EmbeddingModels::BAAI_BGE_SMALL_EN
+            | EmbeddingModels::BAAI_BGE_SMALL_EN_V1_5
+            | EmbeddingModels::BAAI_BGE_BASE_EN
+            | EmbeddingModels::BAAI_BGE_BASE_EN_V1_5
+            | EmbeddingModels::ENTENCE_TRANSFORMERS_ALL_MINILM_L6_V2
+            | EmbeddingModels::XENOVA_FAST_MULTILINGUAL_E5_LARGE => 510,
+        }
+    }
 }
 
 pub enum FastEmbedModels {
@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::init::env_variables::GLOBAL_DATA;
+
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(limit: u32) -> Option<Self> {
+        if limit == 0 {
+            return None;
+        }
+        let capacity = limit as f64;
+        Some(TokenBucket {
+            capacity,
+            available: capacity,
+            refill_per_second: capacity / 60.0,
+            last_refill: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// How long to wait until `amount` is available. Requests larger than the bucket are
+    /// clamped to its capacity so that they can eventually go through.
+    fn wait_time(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.refill_per_second)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+}
+
+/// Limits outbound embedding calls by both requests-per-minute and tokens-per-minute. A limit of
+/// 0 disables that bucket. Callers wait for capacity rather than erroring.
+pub struct RateLimiter {
+    buckets: Mutex<(Option<TokenBucket>, Option<TokenBucket>)>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32, tokens_per_minute: u32) -> Self {
+        RateLimiter {
+            buckets: Mutex::new((
+                TokenBucket::per_minute(requests_per_minute),
+                TokenBucket::per_minute(tokens_per_minute),
+            )),
+        }
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens`: The (estimated) number of tokens the request will consume
+    ///
+    /// returns: ()
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn acquire(&self, tokens: u32) {
+        loop {
+            let wait = {
+                let mut buckets = match self.buckets.lock() {
+                    Ok(buckets) => buckets,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                let (requests, token_bucket) = &mut *buckets;
+                let now = Instant::now();
+                let mut wait = Duration::ZERO;
+                if let Some(bucket) = requests.as_mut() {
+                    bucket.refill(now);
+                    wait = wait.max(bucket.wait_time(1.0));
+                }
+                if let Some(bucket) = token_bucket.as_mut() {
+                    bucket.refill(now);
+                    wait = wait.max(bucket.wait_time(tokens as f64));
+                }
+                if wait.is_zero() {
+                    if let Some(bucket) = requests.as_mut() {
+                        bucket.take(1.0);
+                    }
+                    if let Some(bucket) = token_bucket.as_mut() {
+                        bucket.take(tokens as f64);
+                    }
+                    return;
+                }
+                wait
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Rough token count for rate limiting purposes (around 4 characters per token for English text)
+pub fn estimate_tokens(text: &str) -> u32 {
+    ((text.len() / 4) as u32).max(1)
+}
+
+static EMBEDDING_RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::const_new();
+
+/// The rate limiter shared by every call to the embedding backend
+pub async fn embedding_rate_limiter() -> &'static RateLimiter {
+    EMBEDDING_RATE_LIMITER
+        .get_or_init(|| async {
+            let global_data = GLOBAL_DATA.read().await;
+            RateLimiter::new(
+                global_data.embedding_requests_per_minute,
+                global_data.embedding_tokens_per_minute,
+            )
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Fires `calls` concurrent acquisitions of `tokens` each, returning when each went through
+    async fn acquire_concurrently(limiter: RateLimiter, calls: usize, tokens: u32) -> Vec<Instant> {
+        let limiter = Arc::new(limiter);
+        let handles: Vec<_> = (0..calls)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                tokio::spawn(async move {
+                    limiter.acquire(tokens).await;
+                    Instant::now()
+                })
+            })
+            .collect();
+        let mut times = vec![];
+        for handle in handles {
+            times.push(handle.await.unwrap());
+        }
+        times.sort();
+        times
+    }
+
+    /// A full bucket lets `burst` units through at once, after that they refill at `per_second`,
+    /// so no window may hold more than that
+    fn assert_rate_never_exceeded(times: &[Instant], units: f64, burst: f64, per_second: f64) {
+        for (i, earliest) in times.iter().enumerate() {
+            for (j, latest) in times.iter().enumerate().skip(i) {
+                let window = latest.duration_since(*earliest).as_secs_f64();
+                let used = (j - i + 1) as f64 * units;
+                assert!(
+                    used <= burst + window * per_second + 1e-6,
+                    "{} units went through in {}s",
+                    used,
+                    window
+                );
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_calls_never_exceed_the_requests_per_minute() {
+        let start = Instant::now();
+        let times = acquire_concurrently(RateLimiter::new(60, 0), 150, 10).await;
+        assert_eq!(times.len(), 150);
+        assert_rate_never_exceeded(&times, 1.0, 60.0, 1.0);
+        // The 90 calls beyond the initial burst have to wait for the bucket to refill
+        assert!(times[149].duration_since(start) >= Duration::from_secs(89));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_calls_never_exceed_the_tokens_per_minute() {
+        let times = acquire_concurrently(RateLimiter::new(0, 600), 30, 100).await;
+        assert_eq!(times.len(), 30);
+        assert_rate_never_exceeded(&times, 100.0, 600.0, 10.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_limits_never_wait() {
+        let start = Instant::now();
+        let times = acquire_concurrently(RateLimiter::new(0, 0), 100, 1000).await;
+        assert!(times.iter().all(|time| *time == start));
+    }
+}
@@ -7,42 +7,49 @@
 mod data;
 mod errors;
 mod gcp;
+mod idempotency;
 mod init;
 mod llm;
+mod metrics;
 mod mongo;
 mod qdrant;
 mod queue;
 mod rabbitmq;
+mod reranking;
 mod routes;
 mod utils;
+mod vector_store;
 mod redis_rs;
 
 use qdrant::client::instantiate_qdrant_client;
 use std::sync::{Arc};
+use std::time::Duration;
 
 use crate::init::env_variables::GLOBAL_DATA;
 use actix_cors::Cors;
 use actix_web::rt::System;
 use actix_web::{middleware::Logger, web, web::Data, App, HttpServer};
 use anyhow::Context;
-use env_logger::Env;
 use tokio::join;
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
 #[cfg(windows)]
 use tokio::signal::windows::ctrl_c;
 use tokio::sync::{RwLock};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
 use crate::init::env_variables::set_all_env_vars;
-use crate::rabbitmq::consume::subscribe_to_queue;
-use crate::rabbitmq::models::RabbitConnect;
+use crate::rabbitmq::consume::consume_with_reconnect;
+use crate::rabbitmq::models::{QueueBinding, RabbitConnect};
+use crate::rabbitmq::shutdown::{run_until_signal, Shutdown};
 use routes::api_routes::{
-    bulk_upsert_data_to_collection, create_collection, delete_collection, health_check,
-    list_collections, lookup_data_point, scroll_data, upsert_data_point_to_collection,
+    bulk_upsert_data_to_collection, count_points, create_collection, delete_collection,
+    health_check, list_collections, lookup_data_point, prometheus_metrics, readiness_check,
+    scroll_data, upsert_data_point_to_collection,
 };
 use crate::mongo::client::start_mongo_connection;
 use crate::queue::queuing::{MyQueue, Control};
-use crate::rabbitmq::client::{bind_queue_to_exchange, channel_rabbitmq, connect_rabbitmq};
 
 pub fn init(config: &mut web::ServiceConfig) {
     let webapp_url =
@@ -53,6 +60,9 @@ pub fn init(config: &mut web::ServiceConfig) {
         .supports_credentials()
         .allow_any_header();
 
+    // Probes and Prometheus expect these at the root rather than under the API scope
+    config.service(prometheus_metrics);
+    config.service(readiness_check);
     config.service(
         web::scope("/api/v1")
             .wrap(cors)
@@ -63,7 +73,8 @@ pub fn init(config: &mut web::ServiceConfig) {
             .service(upsert_data_point_to_collection)
             .service(bulk_upsert_data_to_collection)
             .service(lookup_data_point)
-            .service(scroll_data),
+            .service(scroll_data)
+            .service(count_points),
     );
 }
 
@@ -83,46 +94,50 @@ async fn main() -> std::io::Result<()> {
         }
     };
     let mongo_connection = start_mongo_connection().await.unwrap();
-    let app_qdrant_client = Arc::new(RwLock::new(qdrant_client));
+    let app_qdrant_client = Arc::new(qdrant_client);
     let qdrant_connection_for_rabbitmq = Arc::clone(&app_qdrant_client);
     let queue: Arc<RwLock<MyQueue<String>>> = Arc::new(RwLock::new(Control::optimised(global_data.thread_percentage_utilisation)));
     // let redis_connection_pool: Arc<Mutex<RedisConnection>> = Arc::new(Mutex::new(redis_pool));
     let mongo_client_clone = Arc::new(RwLock::new(mongo_connection));
+    let app_mongo_client = Arc::clone(&mongo_client_clone);
     let rabbitmq_connection_details = RabbitConnect {
         host: global_data.rabbitmq_host.clone(),
         port: global_data.rabbitmq_port.clone(),
         username: global_data.rabbitmq_username.clone(),
         password: global_data.rabbitmq_password.clone(),
     };
-    let mut connection = connect_rabbitmq(&rabbitmq_connection_details).await;
-    let mut channel = channel_rabbitmq(&connection).await;
-    bind_queue_to_exchange(
-        &mut connection,
-        &mut channel,
-        &rabbitmq_connection_details,
-        &global_data.rabbitmq_exchange,
-        &global_data.rabbitmq_stream,
-        &global_data.rabbitmq_routing_key,
-    )
-        .await;
+    let binding = QueueBinding {
+        exchange: global_data.rabbitmq_exchange.clone(),
+        queue: global_data.rabbitmq_stream.clone(),
+        routing_key: global_data.rabbitmq_routing_key.clone(),
+    };
+    let shutdown = Arc::new(Shutdown::new(Duration::from_secs(
+        global_data.shutdown_grace_period_secs,
+    )));
     let rabbitmq_stream = tokio::spawn(async move {
-        let _ = subscribe_to_queue(
+        let consumer = consume_with_reconnect(
             Arc::clone(&qdrant_connection_for_rabbitmq),
             Arc::clone(&queue),
             Arc::clone(&mongo_client_clone),
             // Arc::clone(&redis_connection_pool),
-            &channel,
-            &global_data.rabbitmq_stream,
-        )
-            .await;
+            rabbitmq_connection_details,
+            binding,
+            Arc::clone(&shutdown),
+        );
+        run_until_signal(shutdown, consumer).await;
     });
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    // Closed spans are logged with their elapsed time, log records (e.g. from actix) are forwarded too
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
     let web_task = tokio::spawn(async move {
         println!("Running on http://{}:{}", host.clone(), port.clone());
         let server = HttpServer::new(move || {
             App::new()
                 .wrap(Logger::default())
                 .app_data(Data::new(Arc::clone(&app_qdrant_client)))
+                .app_data(Data::new(Arc::clone(&app_mongo_client)))
                 .configure(init)
         })
             .bind(format!("{}:{}", host, port))?
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register_counter(name: &str, help: &str) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), &["datasource_id"])
+        .expect("metric options are valid");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("metric is only registered once");
+    counter
+}
+
+fn register_histogram(name: &str, help: &str) -> HistogramVec {
+    let histogram = HistogramVec::new(HistogramOpts::new(name, help), &["datasource_id"])
+        .expect("metric options are valid");
+    REGISTRY
+        .register(Box::new(histogram.clone()))
+        .expect("metric is only registered once");
+    histogram
+}
+
+pub static MESSAGES_PROCESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_counter(
+        "vector_db_proxy_messages_processed_total",
+        "Number of messages processed",
+    )
+});
+
+pub static POINTS_UPSERTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_counter(
+        "vector_db_proxy_points_upserted_total",
+        "Number of points written to Qdrant",
+    )
+});
+
+pub static EMBEDDING_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_counter(
+        "vector_db_proxy_embedding_failures_total",
+        "Number of messages whose records failed to embed",
+    )
+});
+
+pub static EMBEDDING_CACHE_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_counter(
+        "vector_db_proxy_embedding_cache_hits_total",
+        "Number of embeddings served from the embedding cache",
+    )
+});
+
+pub static EMBEDDING_CACHE_MISSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_counter(
+        "vector_db_proxy_embedding_cache_misses_total",
+        "Number of embeddings that had to be requested from the model",
+    )
+});
+
+pub static EMBEDDING_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram(
+        "vector_db_proxy_embedding_latency_seconds",
+        "Time taken to embed the records of a message",
+    )
+});
+
+pub static UPSERT_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram(
+        "vector_db_proxy_upsert_latency_seconds",
+        "Time taken to upsert a batch of points to Qdrant",
+    )
+});
+
+/// Renders every registered metric in the Prometheus text exposition format
+pub fn gather_metrics() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
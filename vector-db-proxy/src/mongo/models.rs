@@ -2,6 +2,7 @@ use bson::DateTime;
 use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DatasourceConnectionSettings {
@@ -38,10 +39,33 @@ pub struct DataSources {
     pub chunkCharacter: Option<String>,
     pub lastSyncedDate: Option<DateTime>,
     pub embeddingField: Option<String>,
+    pub embedFields: Option<Vec<String>>,
+    pub primaryKeyField: Option<String>,
+    pub recordSchema: Option<RecordSchema>,
+    pub skipInvalidRecords: Option<bool>,
+    pub hybridSearch: Option<bool>,
     pub createdDate: Option<DateTime>,
     pub status: String,
 }
 
+/// JSON types a record field can be required to have
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+}
+
+/// Fields every record of a datasource must have, along with their expected type
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordSchema {
+    pub requiredFields: HashMap<String, JsonType>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum ChunkingStrategy {
     SEMANTIC_CHUNKING,
@@ -90,3 +114,10 @@ pub struct Credentials {
     pub createdDate: Option<DateTime>,
     pub credentials: Option<CredentialsObj>,
 }
+
+/// Marker for a message that was fully ingested, keyed by datasource and message id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProcessedMessage {
+    pub _id: String,
+    pub processedDate: DateTime,
+}
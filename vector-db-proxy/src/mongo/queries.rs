@@ -3,9 +3,9 @@ use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use mongodb::{Collection, Database};
 use std::str::FromStr;
-use mongodb::options::{FindOneOptions};
+use mongodb::options::{FindOneOptions, UpdateOptions};
 
-use crate::mongo::models::{DataSources, Model, Credentials, CredentialsObj};
+use crate::mongo::models::{DataSources, Model, Credentials, CredentialsObj, ProcessedMessage};
 
 pub async fn get_datasource(db: &Database, datasource_id: &str) -> Result<Option<DataSources>> {
     let datasources_collection: Collection<DataSources> = db.collection("datasources");
@@ -143,3 +143,30 @@ pub async fn get_model_credentials(
         }
     }
 }
+
+pub async fn is_message_processed(db: &Database, key: &str) -> Result<bool> {
+    let processed_messages_collection = db.collection::<ProcessedMessage>("processedmessages");
+    match processed_messages_collection
+        .find_one(doc! {"_id": key}, None)
+        .await
+    {
+        Ok(processed_message) => Ok(processed_message.is_some()),
+        Err(e) => Err(anyhow!("Failed to look up processed message: {}", e)),
+    }
+}
+
+pub async fn mark_message_processed(db: &Database, key: &str) -> Result<()> {
+    let processed_messages_collection = db.collection::<ProcessedMessage>("processedmessages");
+    let options = UpdateOptions::builder().upsert(true).build();
+    match processed_messages_collection
+        .update_one(
+            doc! {"_id": key},
+            doc! {"$set": {"processedDate": mongodb::bson::DateTime::now()}},
+            options,
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Failed to record processed message: {}", e)),
+    }
+}
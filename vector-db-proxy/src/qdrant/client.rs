@@ -1,9 +1,73 @@
 use anyhow::Result;
 use qdrant_client::prelude::*;
 use crate::init::env_variables::GLOBAL_DATA;
+use crate::init::models::GlobalData;
+use crate::qdrant::models::QdrantConfig;
 
 pub async fn instantiate_qdrant_client() -> Result<QdrantClient> {
     let global_data = GLOBAL_DATA.read().await;
-    let client = QdrantClient::from_url(global_data.qdrant_uri.as_str());
+    build_qdrant_client(&qdrant_config(&global_data))
+}
+
+/// How to reach Qdrant, shared by the gRPC client and the REST calls made by `Qdrant`
+pub fn qdrant_config(global_data: &GlobalData) -> QdrantConfig {
+    QdrantConfig {
+        url: global_data.qdrant_uri.clone(),
+        // Read straight from the environment rather than GlobalData, which is Debug/Serialize
+        api_key: dotenv::var("QDRANT_API_KEY").ok().filter(|key| !key.is_empty()),
+        tls: global_data.qdrant_tls,
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `config`: Url, optional api key and whether to use TLS. Without an api key or TLS this is a
+/// plain text connection, which is what a local Qdrant instance expects
+///
+/// returns: Result<QdrantClient, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn build_qdrant_client(config: &QdrantConfig) -> Result<QdrantClient> {
+    let client = QdrantClient::from_url(config.endpoint().as_str())
+        .with_api_key(config.api_key.clone());
     client.build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(api_key: Option<&str>, tls: bool) -> QdrantConfig {
+        QdrantConfig {
+            url: "http://qdrant.internal:6334".to_string(),
+            api_key: api_key.map(str::to_string),
+            tls,
+        }
+    }
+
+    #[test]
+    fn an_api_key_is_sent_with_every_request() {
+        let client = build_qdrant_client(&config(Some("secret"), true)).unwrap();
+        assert_eq!(client.cfg.api_key.as_deref(), Some("secret"));
+        assert_eq!(client.cfg.uri, "https://qdrant.internal:6334");
+    }
+
+    #[test]
+    fn without_an_api_key_or_tls_the_connection_is_plain_text() {
+        let client = build_qdrant_client(&config(None, false)).unwrap();
+        assert_eq!(client.cfg.api_key, None);
+        assert_eq!(client.cfg.uri, "http://qdrant.internal:6334");
+    }
+
+    #[test]
+    fn the_api_key_is_redacted_from_debug_output() {
+        let debug = format!("{:?}", config(Some("secret"), true));
+        assert!(!debug.contains("secret"));
+    }
+}
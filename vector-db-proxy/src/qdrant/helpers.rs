@@ -3,19 +3,44 @@ use anyhow::{anyhow, Result};
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::Value;
 use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::value::Kind;
 use qdrant_client::qdrant::vectors::VectorsOptions;
-use qdrant_client::qdrant::{PointStruct, ScrollPoints, ScrollResponse};
+use qdrant_client::qdrant::{
+    Distance, PointId, PointStruct, ScoredPoint, ScrollPoints, ScrollResponse, SparseIndices,
+    Vector,
+};
+use futures::stream::{self, StreamExt};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+use mongodb::bson::oid::ObjectId;
 use mongodb::Database;
+use std::str::FromStr;
 use tokio::sync::RwLock;
+use tonic::{Code, Status};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
-use crate::hash_map_values_as_serde_values;
+use crate::llm::cache::embedding_cache;
+use crate::llm::circuit_breaker::embedding_circuit_breaker;
 use crate::llm::models::EmbeddingModels;
+use crate::llm::rate_limiter::{embedding_rate_limiter, estimate_tokens};
 use crate::llm::utils::embed_text;
-use crate::qdrant::models::ScrollResults;
+use crate::data::errors::ProcessError;
+use crate::data::models::RecordSnapshot;
+use crate::data::sparse_encoding::{encode_document, SparseVector};
+use crate::data::text_splitting::{split_text_by_tokens, ChunkTokenizer};
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::metrics::utils::{EMBEDDING_CACHE_HITS, EMBEDDING_CACHE_MISSES};
+use crate::mongo::models::DataSources;
+use crate::mongo::queries::get_datasource;
+use crate::utils::maths::l2_normalize;
+use crate::qdrant::models::{
+    EmbeddingOptions, HashMapValues, IdStrategy, OversizeBehavior, RetryPolicy, ScrollResults,
+    DENSE_VECTOR_NAME, SPARSE_VECTOR_NAME,
+};
 
 ///
 ///
@@ -31,10 +56,10 @@ use crate::qdrant::models::ScrollResults;
 ///
 /// ```
 pub async fn get_next_page(
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    qdrant_conn: Arc<QdrantClient>,
     scroll_point: &ScrollPoints,
 ) -> Result<(ScrollResponse, String)> {
-    let result = qdrant_conn.read().await.scroll(scroll_point).await?;
+    let result = qdrant_conn.scroll(scroll_point).await?;
 
     let mut offset = String::from("Done");
     if let Some(point_id) = result.clone().next_page_offset {
@@ -48,6 +73,23 @@ pub async fn get_next_page(
     Ok((result, offset))
 }
 
+/// The `source_index` stamped into a point's payload, None for points without one
+pub fn point_source_index(point: &PointStruct) -> Option<u64> {
+    match point.payload.get("source_index")?.kind.as_ref()? {
+        Kind::IntegerValue(index) => u64::try_from(*index).ok(),
+        Kind::DoubleValue(index) => Some(*index as u64),
+        _ => None,
+    }
+}
+
+pub fn point_id_to_string(point_id: &Option<PointId>) -> String {
+    match point_id.as_ref().and_then(|id| id.point_id_options.as_ref()) {
+        Some(PointIdOptions::Num(num)) => num.to_string(),
+        Some(PointIdOptions::Uuid(uuid)) => uuid.to_owned(),
+        None => String::new(),
+    }
+}
+
 pub fn get_scroll_results(result: ScrollResponse) -> Result<Vec<ScrollResults>> {
     let mut response: Vec<ScrollResults> = vec![];
     for result in result.result {
@@ -102,36 +144,26 @@ pub fn get_scroll_results(result: ScrollResponse) -> Result<Vec<ScrollResults>>
 /// ```
 pub async fn embed_payload(
     mongo_conn: Arc<RwLock<Database>>,
-    data: &HashMap<String, String>,
+    data: &HashMap<String, HashMapValues>,
     text: &String,
     datasource_id: Option<String>,
     embedding_model: EmbeddingModels,
 ) -> Result<PointStruct, anyhow::Error> {
     if !data.is_empty() {
         if let Some(_id) = datasource_id {
-            let payload: HashMap<String, serde_json::Value> =
-                hash_map_values_as_serde_values!(data);
-            if let Ok(metadata) = json!(payload).try_into() {
-                // Embedding sentences using OpenAI ADA2
-                let embedding_vec = embed_text(mongo_conn, _id, vec![text], &embedding_model).await?;
-                // Construct PointStruct to insert into DB
-                if !embedding_vec.is_empty() {
-                    if let Some(embedding) = embedding_vec.into_iter().next() {
-                        let point = PointStruct::new(
-                            Uuid::new_v4().to_string(),
-                            HashMap::from([(
-                                String::from(embedding_model.to_str().unwrap()),
-                                embedding.to_owned(),
-                            )]),
-                            metadata,
-                        );
-                        return Ok(point);
-                    }
-                }
-            } else {
-                return Err(anyhow!(
-                    "Could not convert payload to JSON type. Aborting embedding!"
-                ));
+            // Embedding sentences using OpenAI ADA2
+            let embedding_vec =
+                embed_text(mongo_conn, _id.clone(), vec![text], &embedding_model).await?;
+            // Construct PointStruct to insert into DB
+            if let Some(embedding) = embedding_vec.into_iter().next() {
+                return build_point_struct(
+                    Uuid::new_v4().to_string(),
+                    data,
+                    _id.as_str(),
+                    embedding,
+                    embedding_model,
+                    None,
+                );
             }
         } else {
             return Err(anyhow!(
@@ -142,6 +174,400 @@ pub async fn embed_payload(
     Err(anyhow!("Row is empty"))
 }
 
+/// Derives a stable point ID for a record, namespaced by datasource so that identical rows in
+/// different datasources don't collide.
+pub fn generate_point_id(
+    datasource_id: &str,
+    data: &HashMap<String, HashMapValues>,
+    id_strategy: &IdStrategy,
+) -> String {
+    let key = match id_strategy {
+        IdStrategy::FieldKey(field) if data.contains_key(field) => {
+            // Rows split into several chunks share the key, so the chunk has to be part of the ID
+            let chunk_index = data.get("chunk_index").map(|c| c.to_string()).unwrap_or_default();
+            format!("{}:{}:{}:{}", datasource_id, field, data[field], chunk_index)
+        }
+        _ => {
+            // Sort the payload so that the hash doesn't depend on map ordering. Airbyte stamps
+            // every sync with its own metadata, which would otherwise change the hash of unchanged rows.
+            // Likewise a row keeps its ID when it moves to another position in the message
+            let content: BTreeMap<&String, serde_json::Value> = data
+                .iter()
+                .filter(|(k, _)| !k.starts_with("_airbyte_") && *k != "source_index")
+                .map(|(k, v)| (k, serde_json::Value::from(v.clone())))
+                .collect();
+            format!("{}:{}", datasource_id, json!(content))
+        }
+    };
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, key.as_bytes()).to_string()
+}
+
+/// Builds a point from an already computed embedding, stamping the payload with the datasource
+/// so that it can be scoped in later queries. With a `sparse_vector` the point gets both a
+/// `DENSE_VECTOR_NAME` and a `SPARSE_VECTOR_NAME` vector, otherwise its single vector is named
+/// after the embedding model.
+pub fn build_point_struct(
+    point_id: String,
+    data: &HashMap<String, HashMapValues>,
+    datasource_id: &str,
+    embedding: Vec<f32>,
+    embedding_model: EmbeddingModels,
+    sparse_vector: Option<SparseVector>,
+) -> Result<PointStruct> {
+    let mut payload: HashMap<String, serde_json::Value> = data
+        .iter()
+        .map(|(k, v)| (k.clone(), serde_json::Value::from(v.clone())))
+        .collect();
+    payload.insert("datasource_id".to_string(), json!(datasource_id));
+    let Ok(metadata) = json!(payload).try_into() else {
+        return Err(anyhow!(
+            "Could not convert payload to JSON type. Aborting embedding!"
+        ));
+    };
+    if let Some(sparse_vector) = sparse_vector {
+        let vectors = HashMap::from([
+            (DENSE_VECTOR_NAME.to_string(), Vector::from(embedding)),
+            (
+                SPARSE_VECTOR_NAME.to_string(),
+                Vector {
+                    data: sparse_vector.values,
+                    indices: Some(SparseIndices {
+                        data: sparse_vector.indices,
+                    }),
+                },
+            ),
+        ]);
+        return Ok(PointStruct::new(point_id, vectors, metadata));
+    }
+    let Some(model_name) = embedding_model.to_str() else {
+        return Err(anyhow!("Could not convert model to a string slice"));
+    };
+    Ok(PointStruct::new(
+        point_id,
+        HashMap::from([(String::from(model_name), embedding)]),
+        metadata,
+    ))
+}
+
+///
+///
+/// # Arguments
+///
+/// * `table_chunks`: List of records to embed
+/// * `embedding_field`: The record field that holds the text to embed, unused if
+/// `options.embed_fields` is set
+/// * `options`: Which fields to embed, how to derive point IDs and how to split long text
+///
+/// returns: Result<Vec<PointStruct>, Error> errors are a `ProcessError::RecordFailed` naming the
+/// record that could not be embedded
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[tracing::instrument(
+    skip_all,
+    fields(datasource_id = %datasource_id, record_count = table_chunks.len())
+)]
+pub async fn embed_table_chunks_async(
+    mongo_conn: Arc<RwLock<Database>>,
+    datasource_id: String,
+    table_chunks: Vec<HashMap<String, HashMapValues>>,
+    embedding_field: &str,
+    embedding_model: EmbeddingModels,
+    options: &EmbeddingOptions,
+) -> Result<Vec<PointStruct>> {
+    let tokenizer = ChunkTokenizer::for_model(embedding_model);
+    let mut chunks: Vec<(HashMap<String, HashMapValues>, String)> = vec![];
+    for mut metadata in table_chunks {
+        let text = record_text(&mut metadata, embedding_field, &options.embed_fields)
+            .map_err(|e| record_error(e, &metadata))?;
+        // Long values are split so that they fit in the model's context, each chunk becomes its
+        // own point carrying the rest of the row's payload
+        let text_chunks =
+            split_text_by_tokens(&tokenizer, &text, options.max_tokens, options.overlap);
+        for (chunk_index, text_chunk) in text_chunks.into_iter().enumerate() {
+            let mut chunk_metadata = metadata.clone();
+            chunk_metadata.insert("chunk_index".to_string(), HashMapValues::Serde(json!(chunk_index)));
+            chunk_metadata.insert("page_content".to_string(), HashMapValues::Str(text_chunk.clone()));
+            // Checked before embedding so that skipped points don't cost an embedding request
+            if options.max_payload_bytes > 0
+                && !fit_payload(
+                    &mut chunk_metadata,
+                    options.max_payload_bytes,
+                    options.oversize_behavior,
+                )
+            {
+                warn!(
+                    chunk_index,
+                    max_payload_bytes = options.max_payload_bytes,
+                    "Skipping point whose payload is too large"
+                );
+                continue;
+            }
+            chunks.push((chunk_metadata, text_chunk));
+        }
+    }
+    // Each chunk is one embedding round trip, so up to `max_concurrent_embeddings` of them are
+    // in flight at once. Points come back in completion order
+    let results: Vec<Result<(PointStruct, bool)>> = stream::iter(chunks)
+        .map(|(chunk_metadata, text_chunk)| {
+            let mongo_conn = Arc::clone(&mongo_conn);
+            let datasource_id = datasource_id.as_str();
+            async move {
+                embed_chunk(
+                    mongo_conn,
+                    datasource_id,
+                    &chunk_metadata,
+                    &text_chunk,
+                    embedding_model,
+                    options,
+                )
+                    .await
+                    .map_err(|e| record_error(e, &chunk_metadata))
+            }
+        })
+        .buffer_unordered(options.max_concurrent_embeddings.max(1))
+        .collect()
+        .await;
+    let mut list_of_points: Vec<PointStruct> = Vec::with_capacity(results.len());
+    let mut cache_hits = 0;
+    for result in results {
+        let (point, cache_hit) = result?;
+        if cache_hit {
+            cache_hits += 1;
+        }
+        list_of_points.push(point);
+    }
+    if embedding_cache().await.is_enabled() {
+        let cache_misses = (list_of_points.len() - cache_hits) as u64;
+        EMBEDDING_CACHE_HITS
+            .with_label_values(&[&datasource_id])
+            .inc_by(cache_hits as u64);
+        EMBEDDING_CACHE_MISSES
+            .with_label_values(&[&datasource_id])
+            .inc_by(cache_misses);
+        debug!(
+            cache_hits,
+            embeddings = list_of_points.len(),
+            "Embeddings served from the embedding cache"
+        );
+    }
+    Ok(list_of_points)
+}
+
+/// Wraps an error raised while turning a record into points in `ProcessError::RecordFailed`, so
+/// that logs and callers can tell which record it was
+fn record_error(e: anyhow::Error, metadata: &HashMap<String, HashMapValues>) -> anyhow::Error {
+    let source = match e.downcast::<ProcessError>() {
+        Ok(process_error) => process_error,
+        Err(e) => ProcessError::EmbeddingFailed(e),
+    };
+    anyhow!(ProcessError::RecordFailed {
+        record: RecordSnapshot::from_record(metadata),
+        source: Box::new(source),
+    })
+}
+
+/// Embeds one chunk of a record and builds its point. Returns whether the embedding came from
+/// the cache alongside the point
+async fn embed_chunk(
+    mongo_conn: Arc<RwLock<Database>>,
+    datasource_id: &str,
+    chunk_metadata: &HashMap<String, HashMapValues>,
+    text_chunk: &str,
+    embedding_model: EmbeddingModels,
+    options: &EmbeddingOptions,
+) -> Result<(PointStruct, bool)> {
+    let embedding_text = options.preprocessor.apply(text_chunk);
+    let (mut embedding, cache_hit) = embed_text_cached(
+        mongo_conn,
+        datasource_id.to_string(),
+        &embedding_text,
+        embedding_model,
+        options.embedding_timeout,
+        &options.embedding_retry_policy,
+    )
+        .await?;
+    if options.normalize {
+        l2_normalize(&mut embedding);
+    }
+    let point_id = generate_point_id(datasource_id, chunk_metadata, &options.id_strategy);
+    let sparse_vector = options.sparse_vectors.then(|| encode_document(&embedding_text));
+    let point = build_point_struct(
+        point_id,
+        chunk_metadata,
+        datasource_id,
+        embedding,
+        embedding_model,
+        sparse_vector,
+    )?;
+    Ok((point, cache_hit))
+}
+
+const TRUNCATION_MARKER: &str = "...[truncated]";
+
+fn payload_size(metadata: &HashMap<String, HashMapValues>) -> usize {
+    let payload: HashMap<&String, serde_json::Value> = metadata
+        .iter()
+        .map(|(k, v)| (k, serde_json::Value::from(v.clone())))
+        .collect();
+    serde_json::to_vec(&payload).map_or(0, |bytes| bytes.len())
+}
+
+/// Makes sure a point's payload is at most `max_payload_bytes` once serialised. With
+/// `OversizeBehavior::Truncate` the longest text fields are shortened (and suffixed with
+/// `TRUNCATION_MARKER`) until it fits. Returns false if the point should be skipped instead.
+fn fit_payload(
+    metadata: &mut HashMap<String, HashMapValues>,
+    max_payload_bytes: usize,
+    oversize_behavior: OversizeBehavior,
+) -> bool {
+    // Leaves room for the `datasource_id` field added in `build_point_struct`
+    let max_payload_bytes = max_payload_bytes.saturating_sub(64);
+    let mut size = payload_size(metadata);
+    while size > max_payload_bytes {
+        if oversize_behavior == OversizeBehavior::Skip {
+            return false;
+        }
+        let longest_field = metadata
+            .iter()
+            .filter_map(|(k, v)| match v {
+                HashMapValues::Str(text) if text.len() > TRUNCATION_MARKER.len() => {
+                    Some((k.clone(), text.len()))
+                }
+                _ => None,
+            })
+            .max_by_key(|(_, len)| *len);
+        // Nothing left to truncate, the non text fields alone are too large
+        let Some((field, len)) = longest_field else {
+            return false;
+        };
+        let mut new_len = len
+            .saturating_sub(size - max_payload_bytes)
+            .saturating_sub(TRUNCATION_MARKER.len());
+        if let Some(HashMapValues::Str(text)) = metadata.get_mut(&field) {
+            while !text.is_char_boundary(new_len) {
+                new_len -= 1;
+            }
+            text.truncate(new_len);
+            text.push_str(TRUNCATION_MARKER);
+        }
+        size = payload_size(metadata);
+    }
+    true
+}
+
+/// Picks the text to embed out of a record. If `embed_fields` is empty the `embedding_field` is
+/// moved out of the payload and embedded on its own. Otherwise the listed fields are concatenated
+/// (as `field: value` lines) and every field stays in the payload.
+fn record_text(
+    metadata: &mut HashMap<String, HashMapValues>,
+    embedding_field: &str,
+    embed_fields: &[String],
+) -> Result<String> {
+    if embed_fields.is_empty() {
+        return match metadata.remove(embedding_field) {
+            Some(text_value) => Ok(text_value.to_string()),
+            None => Err(anyhow!(
+                "Record did not contain the embedding field: {}",
+                embedding_field
+            )),
+        };
+    }
+    let lines: Vec<String> = embed_fields
+        .iter()
+        .filter_map(|field| metadata.get(field).map(|value| format!("{}: {}", field, value)))
+        .collect();
+    if lines.is_empty() {
+        return Err(anyhow!(
+            "Record did not contain any of the embed fields: {}",
+            embed_fields.join(", ")
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Embeds a single piece of text, reusing the cached vector if the same text has already been
+/// embedded with the same model. Returns the embedding and whether it came from the cache.
+/// Failed requests, timeouts included, are retried according to `retry_policy`. Every attempt
+/// goes through the shared circuit breaker: once the backend has failed too many times in a row
+/// calls fail straight away with `ProcessError::EmbeddingUnavailable` until the cooldown is over
+pub async fn embed_text_cached(
+    mongo_conn: Arc<RwLock<Database>>,
+    datasource_id: String,
+    text: &String,
+    embedding_model: EmbeddingModels,
+    embedding_timeout: Duration,
+    retry_policy: &RetryPolicy,
+) -> Result<(Vec<f32>, bool)> {
+    let model_name = embedding_model.to_str().unwrap_or_default();
+    // Identical chunks are common (boilerplate, repeated cells) so reuse their vectors
+    let cache = embedding_cache().await;
+    if let Some(cached_embedding) = cache.get(model_name, text) {
+        return Ok((cached_embedding, true));
+    }
+    let breaker = embedding_circuit_breaker().await;
+    let mut attempt = 0;
+    loop {
+        let Some(permit) = breaker.allow() else {
+            return Err(anyhow!(ProcessError::EmbeddingUnavailable));
+        };
+        embedding_rate_limiter()
+            .await
+            .acquire(estimate_tokens(text))
+            .await;
+        let result = with_embedding_timeout(
+            embedding_timeout,
+            embed_text(
+                Arc::clone(&mongo_conn),
+                datasource_id.clone(),
+                vec![text],
+                &embedding_model,
+            ),
+        )
+            .await;
+        match result {
+            Ok(embedding_vec) => {
+                permit.succeeded();
+                let Some(embedding) = embedding_vec.into_iter().next() else {
+                    return Err(anyhow!("No embedding was returned for record"));
+                };
+                cache.insert(model_name, text, embedding.clone());
+                return Ok((embedding, false));
+            }
+            Err(e) => {
+                permit.failed();
+                if attempt >= retry_policy.max_retries || breaker.is_open() {
+                    return Err(e);
+                }
+                let delay = retry_policy.jittered_delay_for_attempt(attempt);
+                warn!(
+                    "Embedding attempt {} failed: {}. Retrying in {:?}",
+                    attempt + 1,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Fails a single embedding request with `ProcessError::EmbeddingTimeout` once it takes longer
+/// than `embedding_timeout`, a hung provider would otherwise stall the whole queue
+async fn with_embedding_timeout<T>(
+    embedding_timeout: Duration,
+    request: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match tokio::time::timeout(embedding_timeout, request).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(ProcessError::EmbeddingTimeout(embedding_timeout))),
+    }
+}
+
 pub async fn reverse_embed_payload(payload: &HashMap<String, Value>) -> Result<Vec<String>> {
     if !payload.is_empty() {
         if let Some(text) = payload.get("text") {
@@ -179,3 +605,484 @@ pub async fn construct_point_struct(
     }
     None
 }
+
+/// Returns true for gRPC errors that are likely to go away on their own (Qdrant restarting,
+/// timeouts, dropped connections). Anything else, such as a missing collection or a vector
+/// dimension mismatch, is treated as permanent.
+pub fn is_transient_error(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<Status>() {
+        Some(status) => match status.code() {
+            Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::Cancelled => true,
+            // The Qdrant client reports connection failures as internal errors
+            Code::Internal => status.message().starts_with("Failed to connect"),
+            _ => false,
+        },
+        None => error.downcast_ref::<tonic::transport::Error>().is_some(),
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `retry_policy`: How many times to retry and how long to wait between attempts
+/// * `operation`: The Qdrant call to make. Called once per attempt
+///
+/// returns: Result<T, Error>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn retry_on_transient_errors<T, F, Fut>(
+    retry_policy: &RetryPolicy,
+    mut operation: F,
+) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < retry_policy.max_retries && is_transient_error(&e) => {
+                let delay = retry_policy.delay_for_attempt(attempt);
+                warn!(
+                    error = %e,
+                    attempt = attempt + 1,
+                    delay_ms = delay.as_millis() as u64,
+                    "Transient error from Qdrant, retrying"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `rankings`: Results of the same query against different vectors, each best first
+/// * `k`: Damping constant, the larger it is the less the top ranks dominate the fused score
+///
+/// Fuses the rankings with reciprocal rank fusion: a point scores `1 / (k + rank)` for every
+/// ranking it appears in, so points ranked well by several searches rise to the top even
+/// though the searches' raw scores aren't comparable
+///
+/// returns: Vec<ScoredPoint> the distinct points, best first, with the fused score as their score
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub fn reciprocal_rank_fusion(rankings: Vec<Vec<ScoredPoint>>, k: f32) -> Vec<ScoredPoint> {
+    let mut fused: HashMap<String, ScoredPoint> = HashMap::new();
+    for ranking in rankings {
+        for (rank, mut point) in ranking.into_iter().enumerate() {
+            let rank_score = 1.0 / (k + rank as f32 + 1.0);
+            fused
+                .entry(point_id_to_string(&point.id))
+                .and_modify(|fused_point| fused_point.score += rank_score)
+                .or_insert_with(|| {
+                    point.score = rank_score;
+                    point
+                });
+        }
+    }
+    let mut points: Vec<ScoredPoint> = fused.into_values().collect();
+    points.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    points
+}
+
+/// Whether a search score passes `min_score`. Qdrant reports euclidean and manhattan scores as
+/// distances, where lower is better, so for those `min_score` is a maximum instead
+pub fn score_meets_threshold(distance: Distance, score: f32, min_score: f32) -> bool {
+    match distance {
+        Distance::Euclid | Distance::Manhattan => score <= min_score,
+        _ => score >= min_score,
+    }
+}
+
+/// Name of the collection holding a datasource's points, `{tenant_id}__{datasource_id}` for a
+/// tenant and the datasource id alone otherwise
+pub fn tenant_collection_name(tenant_id: Option<&str>, datasource_id: &str) -> String {
+    match tenant_id {
+        Some(tenant_id) if !tenant_id.is_empty() => format!("{}__{}", tenant_id, datasource_id),
+        _ => datasource_id.to_string(),
+    }
+}
+
+fn tenant_of_org(org_id: &ObjectId, collection_per_tenant: bool) -> Option<String> {
+    collection_per_tenant.then(|| org_id.to_hex())
+}
+
+/// The tenant a datasource's collection is namespaced by: its org when
+/// `QDRANT_COLLECTION_PER_TENANT` is set, none otherwise so that collections keep the names they
+/// were created with
+pub async fn datasource_tenant(datasource: &DataSources) -> Option<String> {
+    let collection_per_tenant = GLOBAL_DATA.read().await.qdrant_collection_per_tenant;
+    tenant_of_org(&datasource.orgId, collection_per_tenant)
+}
+
+/// `datasource_tenant` for a datasource that still has to be looked up in Mongo
+pub async fn lookup_datasource_tenant(
+    mongo_conn: &Arc<RwLock<Database>>,
+    datasource_id: &str,
+) -> Result<Option<String>> {
+    if !GLOBAL_DATA.read().await.qdrant_collection_per_tenant {
+        return Ok(None);
+    }
+    // get_datasource panics on ids that aren't ObjectIds
+    ObjectId::from_str(datasource_id)?;
+    let mongo = mongo_conn.read().await;
+    match get_datasource(&mongo, datasource_id).await? {
+        Some(datasource) => Ok(datasource_tenant(&datasource).await),
+        None => Err(anyhow!(
+            "Datasource: {} does not exist, its tenant is unknown",
+            datasource_id
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn datasources_are_namespaced_by_their_org_only_when_enabled() {
+        let org_id = ObjectId::new();
+        assert_eq!(tenant_of_org(&org_id, false), None);
+        assert_eq!(tenant_of_org(&org_id, true), Some(org_id.to_hex()));
+        assert_eq!(tenant_collection_name(None, "ds"), "ds");
+        assert_eq!(tenant_collection_name(Some("org_a"), "ds"), "org_a__ds");
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_until_the_upsert_succeeds() {
+        let attempts = AtomicUsize::new(0);
+        let successes = AtomicUsize::new(0);
+        let result = retry_on_transient_errors(&RetryPolicy::new(3, 1), || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(anyhow!(Status::unavailable("Qdrant is restarting")));
+            }
+            successes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(successes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = retry_on_transient_errors(&RetryPolicy::new(3, 1), || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow!(Status::not_found("Collection does not exist")))
+        })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unclassified_errors_are_permanent() {
+        assert!(!is_transient_error(&anyhow!(Status::unknown("Wrong input"))));
+        assert!(!is_transient_error(&anyhow!(Status::internal("Service internal error"))));
+        assert!(is_transient_error(&anyhow!(Status::internal("Failed to connect to Qdrant"))));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = retry_on_transient_errors(&RetryPolicy::new(2, 1), || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow!(Status::deadline_exceeded("timed out")))
+        })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    fn record(fields: &[(&str, HashMapValues)]) -> HashMap<String, HashMapValues> {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn content_hash_ids_are_stable_for_the_same_row() {
+        let row = record(&[
+            ("name", HashMapValues::Str("Acme".to_string())),
+            ("employees", HashMapValues::Int(12)),
+        ]);
+        // Airbyte metadata and the row's position in the message change between syncs
+        let mut resynced_row = row.clone();
+        resynced_row.insert(
+            "_airbyte_extracted_at".to_string(),
+            HashMapValues::Str("2024-04-01".to_string()),
+        );
+        resynced_row.insert("source_index".to_string(), HashMapValues::Int(7));
+        let id = generate_point_id("ds", &row, &IdStrategy::ContentHash);
+        assert_eq!(id, generate_point_id("ds", &resynced_row, &IdStrategy::ContentHash));
+        assert_ne!(id, generate_point_id("other_ds", &row, &IdStrategy::ContentHash));
+        let mut changed_row = row.clone();
+        changed_row.insert("employees".to_string(), HashMapValues::Int(13));
+        assert_ne!(id, generate_point_id("ds", &changed_row, &IdStrategy::ContentHash));
+    }
+
+    #[test]
+    fn field_key_ids_only_depend_on_the_key() {
+        let strategy = IdStrategy::FieldKey("id".to_string());
+        let row = record(&[
+            ("id", HashMapValues::Int(1)),
+            ("status", HashMapValues::Str("open".to_string())),
+        ]);
+        let mut updated_row = row.clone();
+        updated_row.insert("status".to_string(), HashMapValues::Str("closed".to_string()));
+        let id = generate_point_id("ds", &row, &strategy);
+        assert_eq!(id, generate_point_id("ds", &updated_row, &strategy));
+        // Chunks of the same row get their own point
+        let mut second_chunk = row.clone();
+        second_chunk.insert("chunk_index".to_string(), HashMapValues::Int(1));
+        assert_ne!(id, generate_point_id("ds", &second_chunk, &strategy));
+        // Rows without the key fall back to hashing their content
+        let keyless_row = record(&[("status", HashMapValues::Str("open".to_string()))]);
+        assert_eq!(
+            generate_point_id("ds", &keyless_row, &strategy),
+            generate_point_id("ds", &keyless_row, &IdStrategy::ContentHash)
+        );
+    }
+
+    #[test]
+    fn excluded_numeric_columns_do_not_change_the_embedded_text() {
+        let embed_fields = vec!["title".to_string(), "body".to_string()];
+        let mut row = record(&[
+            ("title", HashMapValues::Str("Refund policy".to_string())),
+            ("body", HashMapValues::Str("Refunds take five days".to_string())),
+            ("views", HashMapValues::Int(10)),
+        ]);
+        let mut busier_row = row.clone();
+        busier_row.insert("views".to_string(), HashMapValues::Int(99_999));
+        let text = record_text(&mut row, "", &embed_fields).unwrap();
+        // The vector only depends on the embedded text, so the same text means the same vector
+        assert_eq!(text, record_text(&mut busier_row, "", &embed_fields).unwrap());
+        assert_eq!(text, "title: Refund policy\nbody: Refunds take five days");
+        assert!(matches!(row.get("views"), Some(HashMapValues::Int(10))));
+        assert!(row.contains_key("title"));
+    }
+
+    #[test]
+    fn without_embed_fields_the_embedding_field_is_moved_out_of_the_payload() {
+        let mut row = record(&[
+            ("text", HashMapValues::Str("Refunds take five days".to_string())),
+            ("views", HashMapValues::Int(10)),
+        ]);
+        assert_eq!(record_text(&mut row, "text", &[]).unwrap(), "Refunds take five days");
+        assert!(!row.contains_key("text"));
+        assert!(row.contains_key("views"));
+        assert!(record_text(&mut row, "text", &[]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn embedding_requests_that_hang_time_out() {
+        let timeout = Duration::from_secs(5);
+        let hung_backend = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(vec![0.0_f32])
+        };
+        let error = with_embedding_timeout(timeout, hung_backend).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ProcessError>(),
+            Some(ProcessError::EmbeddingTimeout(elapsed)) if *elapsed == timeout
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn embedding_requests_within_the_timeout_succeed() {
+        let slow_backend = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(vec![0.5_f32])
+        };
+        let embedding = with_embedding_timeout(Duration::from_secs(5), slow_backend).await;
+        assert_eq!(embedding.unwrap(), vec![0.5]);
+    }
+
+    fn batch_with_one_oversized_record() -> Vec<HashMap<String, HashMapValues>> {
+        (0..4)
+            .map(|i| {
+                let text = match i {
+                    2 => "x".repeat(10_000),
+                    _ => format!("row {}", i),
+                };
+                record(&[
+                    ("page_content", HashMapValues::Str(text)),
+                    ("row", HashMapValues::Int(i)),
+                ])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oversized_points_are_skipped_and_the_rest_of_the_batch_kept() {
+        let mut batch = batch_with_one_oversized_record();
+        let kept: Vec<bool> = batch
+            .iter_mut()
+            .map(|metadata| fit_payload(metadata, 1024, OversizeBehavior::Skip))
+            .collect();
+        assert_eq!(kept, vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn oversized_points_are_truncated_to_fit() {
+        let mut batch = batch_with_one_oversized_record();
+        for metadata in batch.iter_mut() {
+            assert!(fit_payload(metadata, 1024, OversizeBehavior::Truncate));
+            assert!(payload_size(metadata) <= 1024);
+        }
+        let Some(HashMapValues::Str(text)) = batch[2].get("page_content") else {
+            panic!("page_content should still be text");
+        };
+        assert!(text.ends_with(TRUNCATION_MARKER));
+        // Points that already fit are left as is
+        assert!(matches!(
+            batch[0].get("page_content"),
+            Some(HashMapValues::Str(text)) if text == "row 0"
+        ));
+    }
+
+    #[tokio::test]
+    async fn large_batches_are_embedded_concurrently_into_one_point_per_record() {
+        // The client only connects once it is used, and every embedding below is cached
+        let database = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap()
+            .database("test");
+        let model = EmbeddingModels::OAI_SMALL;
+        let options = EmbeddingOptions {
+            max_concurrent_embeddings: 8,
+            ..Default::default()
+        };
+        let row_count = 500;
+        let mut records = Vec::with_capacity(row_count);
+        for row in 0..row_count {
+            let text = format!("cached row {}", row);
+            let embedding_text = options.preprocessor.apply(&text);
+            embedding_cache()
+                .await
+                .insert(model.to_str().unwrap(), &embedding_text, vec![row as f32, 1.0]);
+            records.push(record(&[
+                ("text", HashMapValues::Str(text)),
+                ("row", HashMapValues::Int(row as i64)),
+            ]));
+        }
+        let points = embed_table_chunks_async(
+            Arc::new(RwLock::new(database)),
+            "ds".to_string(),
+            records,
+            "text",
+            model,
+            &options,
+        )
+            .await
+            .unwrap();
+        assert_eq!(points.len(), row_count);
+        // Points come back in completion order, but each still carries its own record's vector
+        for point in points {
+            let Some(Kind::IntegerValue(row)) = &point.payload["row"].kind else {
+                panic!("row should be an integer");
+            };
+            let Some(VectorsOptions::Vectors(vectors)) =
+                point.vectors.and_then(|vectors| vectors.vectors_options)
+            else {
+                panic!("points should have named vectors");
+            };
+            assert_eq!(vectors.vectors[model.to_str().unwrap()].data[0], *row as f32);
+        }
+    }
+
+    fn scored_point(id: u64, score: f32) -> ScoredPoint {
+        ScoredPoint {
+            id: Some(PointId::from(id)),
+            score,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn points_ranked_by_both_searches_are_fused_to_the_top() {
+        // Raw scores of the two rankings aren't comparable, only the ranks count
+        let dense = vec![scored_point(1, 0.9), scored_point(2, 0.8), scored_point(3, 0.7)];
+        let sparse = vec![scored_point(3, 12.0), scored_point(4, 9.0), scored_point(2, 1.0)];
+        let fused = reciprocal_rank_fusion(vec![dense, sparse], 60.0);
+        let ids: Vec<String> = fused.iter().map(|point| point_id_to_string(&point.id)).collect();
+        assert_eq!(ids, vec!["3", "2", "1", "4"]);
+        assert_eq!(fused[0].score, 1.0 / 63.0 + 1.0 / 61.0);
+        assert_eq!(fused[1].score, 1.0 / 62.0 + 1.0 / 63.0);
+        assert_eq!(fused[2].score, 1.0 / 61.0);
+        assert_eq!(fused[3].score, 1.0 / 62.0);
+    }
+
+    #[test]
+    fn similarity_scores_must_reach_min_score() {
+        for distance in [Distance::Cosine, Distance::Dot] {
+            assert!(score_meets_threshold(distance, 0.9, 0.8));
+            assert!(score_meets_threshold(distance, 0.8, 0.8));
+            assert!(!score_meets_threshold(distance, 0.7, 0.8));
+        }
+    }
+
+    #[test]
+    fn distances_must_stay_within_min_score() {
+        for distance in [Distance::Euclid, Distance::Manhattan] {
+            assert!(score_meets_threshold(distance, 0.7, 0.8));
+            assert!(score_meets_threshold(distance, 0.8, 0.8));
+            assert!(!score_meets_threshold(distance, 0.9, 0.8));
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_hits_are_counted_per_datasource() {
+        // The client only connects once it is used, and the one embedding below is cached
+        let database = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap()
+            .database("test");
+        let model = EmbeddingModels::OAI_SMALL;
+        let options = EmbeddingOptions::default();
+        embedding_cache()
+            .await
+            .insert(model.to_str().unwrap(), "counted cache hit", vec![1.0, 0.0]);
+        let records = vec![record(&[("text", HashMapValues::Str("counted cache hit".into()))])];
+        embed_table_chunks_async(
+            Arc::new(RwLock::new(database)),
+            "ds_cache_metrics".to_string(),
+            records,
+            "text",
+            model,
+            &options,
+        )
+            .await
+            .unwrap();
+        let hits = EMBEDDING_CACHE_HITS.with_label_values(&["ds_cache_metrics"]).get();
+        let misses = EMBEDDING_CACHE_MISSES.with_label_values(&["ds_cache_metrics"]).get();
+        assert_eq!((hits, misses), (1, 0));
+    }
+}
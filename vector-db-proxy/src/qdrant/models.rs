@@ -1,7 +1,15 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::vectors::VectorsOptions;
+use qdrant_client::qdrant::{RetrievedPoint, SnapshotDescription};
 use serde_json::Value;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::fmt;
+use std::time::Duration;
+
+use crate::data::preprocessing::TextPreprocessor;
 
 
 #[derive(Serialize, Deserialize)]
@@ -17,6 +25,61 @@ pub struct PointSearchResults {
     pub payload: HashMap<String, qdrant_client::prelude::Value>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedPoint {
+    pub id: String,
+    /// Position of the point's record in its message, if it was stamped with one
+    pub source_index: Option<u64>,
+    pub reason: String,
+}
+
+/// Outcome of a bulk upsert. A fully successful upsert has an empty `failed` list.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UpsertReport {
+    pub succeeded: usize,
+    pub failed: Vec<FailedPoint>,
+}
+
+/// Outcome of replacing a datasource's points with a new full sync
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReplaceReport {
+    pub upserted: UpsertReport,
+    /// Points of the previous sync that were not part of the new one
+    pub deleted: u64,
+}
+
+/// Outcome of validating a message without upserting it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DryRunReport {
+    /// Records parsed from the message
+    pub records: usize,
+    /// Points that would have been written, records that are split get one point per chunk
+    pub points: usize,
+    /// Size of the embeddings the datasource's model produced, None if nothing was embedded
+    pub vector_dimension: Option<usize>,
+    /// Embedding length configured for the datasource's model, which the collection is created with
+    pub expected_dimension: u64,
+    /// Schema violations, one per offending field, prefixed with the record's index
+    pub invalid_records: Vec<String>,
+}
+
+/// Sent after every upsert chunk of a bulk upsert. `total` is the number of points being upserted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IngestProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    /// Vector similarity score
+    pub score: f32,
+    /// Only set when the wrapper has a reranker, hits are then ordered by this score
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f32>,
+    pub payload: HashMap<String, qdrant_client::prelude::Value>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetCollectionsResults {
     pub collection_name: String,
@@ -38,6 +101,9 @@ pub struct ScrollResults {
 pub enum HashMapValues {
     Serde(Value),
     Str(String),
+    Int(i64),
+    Float(f64),
+    List(Vec<HashMapValues>),
 }
 
 impl fmt::Display for HashMapValues {
@@ -45,6 +111,257 @@ impl fmt::Display for HashMapValues {
         match self {
             HashMapValues::Serde(serde) => write!(f, "{}", Value::to_string(serde)),
             HashMapValues::Str(s) => write!(f, "{}", s.to_owned()),
+            HashMapValues::Int(i) => write!(f, "{}", i),
+            // Formatted like the JSON number it came from, e.g. `1.0` rather than `1`
+            HashMapValues::Float(n) => write!(f, "{}", Value::from(*n)),
+            HashMapValues::List(list) => {
+                let items: Vec<String> = list.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+impl From<Value> for HashMapValues {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(s) => HashMapValues::Str(s),
+            Value::Number(n) if n.is_i64() => HashMapValues::Int(n.as_i64().unwrap_or_default()),
+            Value::Number(n) if n.is_f64() => HashMapValues::Float(n.as_f64().unwrap_or_default()),
+            // Integers too large for an i64 are kept as they are
+            other => HashMapValues::Serde(other),
+        }
+    }
+}
+
+impl From<HashMapValues> for Value {
+    fn from(value: HashMapValues) -> Self {
+        match value {
+            HashMapValues::Serde(serde) => serde,
+            HashMapValues::Str(s) => Value::String(s),
+            HashMapValues::Int(i) => Value::from(i),
+            HashMapValues::Float(n) => Value::from(n),
+            HashMapValues::List(list) => Value::Array(list.into_iter().map(Value::from).collect()),
+        }
+    }
+}
+
+/// How point IDs are derived. Both strategies are deterministic so that re-ingesting the same row
+/// overwrites its previous point rather than adding a duplicate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdStrategy {
+    /// Hash of the record's payload (including the embedded text)
+    ContentHash,
+    /// Value of the given payload field. Falls back to `ContentHash` for records missing the field
+    FieldKey(String),
+}
+
+impl From<Option<String>> for IdStrategy {
+    fn from(primary_key_field: Option<String>) -> Self {
+        match primary_key_field {
+            Some(field) if !field.is_empty() => IdStrategy::FieldKey(field),
+            _ => IdStrategy::ContentHash,
+        }
+    }
+}
+
+/// Connection settings for the Qdrant client
+#[derive(Clone)]
+pub struct QdrantConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    /// Connect over TLS. Forces an `https://` scheme on the url
+    pub tls: bool,
+}
+
+// Hand written so that the api key never ends up in logs
+impl fmt::Debug for QdrantConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QdrantConfig")
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl QdrantConfig {
+    /// The url the client should connect to. The Qdrant client decides whether to use TLS based
+    /// on the url scheme
+    pub fn endpoint(&self) -> String {
+        if !self.tls {
+            return self.url.clone();
+        }
+        match self.url.split_once("://") {
+            Some((_, rest)) => format!("https://{}", rest),
+            None => format!("https://{}", self.url),
+        }
+    }
+}
+
+/// What to do with a point whose payload is larger than `EmbeddingOptions::max_payload_bytes`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OversizeBehavior {
+    /// Cut the longest text fields down until the payload fits, marking them as truncated
+    Truncate,
+    /// Leave the point out of the upsert
+    Skip,
+}
+
+impl From<String> for OversizeBehavior {
+    fn from(value: String) -> Self {
+        match value.to_lowercase().as_str() {
+            "skip" => OversizeBehavior::Skip,
+            _ => OversizeBehavior::Truncate,
+        }
+    }
+}
+
+/// A snapshot of a datasource's collection, as stored by Qdrant
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub creation_time: Option<DateTime<Utc>>,
+    /// Size of the snapshot file in bytes
+    pub size: i64,
+}
+
+impl From<SnapshotDescription> for SnapshotInfo {
+    fn from(description: SnapshotDescription) -> Self {
+        SnapshotInfo {
+            name: description.name,
+            creation_time: description
+                .creation_time
+                .and_then(|t| DateTime::from_timestamp(t.seconds, t.nanos.max(0) as u32)),
+            size: description.size,
+        }
+    }
+}
+
+/// A point as stored in a datasource's collection, as returned by `Qdrant::scroll`
+#[derive(Serialize, Debug, Clone)]
+pub struct StoredPoint {
+    pub id: String,
+    pub payload: HashMap<String, qdrant_client::qdrant::Value>,
+    /// The point's dense vector, only set when vectors were requested
+    pub vector: Option<Vec<f32>>,
+}
+
+impl From<RetrievedPoint> for StoredPoint {
+    fn from(point: RetrievedPoint) -> Self {
+        let id = match point.id.and_then(|id| id.point_id_options) {
+            Some(PointIdOptions::Num(num)) => num.to_string(),
+            Some(PointIdOptions::Uuid(uuid)) => uuid,
+            None => String::new(),
+        };
+        let vector = match point.vectors.and_then(|vectors| vectors.vectors_options) {
+            Some(VectorsOptions::Vector(vector)) => Some(vector.data),
+            // Points have a single dense vector, named after the model or `DENSE_VECTOR_NAME`,
+            // alongside an optional sparse one
+            Some(VectorsOptions::Vectors(named_vectors)) => named_vectors
+                .vectors
+                .into_values()
+                .find(|vector| vector.indices.is_none())
+                .map(|vector| vector.data),
+            None => None,
+        };
+        StoredPoint {
+            id,
+            payload: point.payload,
+            vector,
+        }
+    }
+}
+
+/// Named vectors of collections created for hybrid search. Collections without sparse vectors
+/// keep naming their vector after the embedding model
+pub const DENSE_VECTOR_NAME: &str = "dense";
+pub const SPARSE_VECTOR_NAME: &str = "sparse";
+
+/// Settings for turning records into points in `embed_table_chunks_async`
+#[derive(Debug, Clone)]
+pub struct EmbeddingOptions {
+    /// Fields whose values are concatenated into the embedded text. When empty the datasource's
+    /// embedding field is embedded on its own
+    pub embed_fields: Vec<String>,
+    pub id_strategy: IdStrategy,
+    /// Text longer than this many tokens is split into several points
+    pub max_tokens: usize,
+    /// Number of tokens consecutive chunks share
+    pub overlap: usize,
+    /// How long a single embedding request may take
+    pub embedding_timeout: Duration,
+    /// Scale vectors to unit length before upserting, needed for dot product collections to
+    /// behave like cosine
+    pub normalize: bool,
+    /// Largest payload, as serialised JSON, a single point may carry. 0 disables the check
+    pub max_payload_bytes: usize,
+    pub oversize_behavior: OversizeBehavior,
+    /// Number of embedding requests in flight at once
+    pub max_concurrent_embeddings: usize,
+    /// How failed embedding requests are retried. Delays are jittered so that concurrent requests
+    /// don't retry in lockstep
+    pub embedding_retry_policy: RetryPolicy,
+    /// Also encode each chunk as a sparse keyword vector, stored alongside the dense embedding as
+    /// named vectors so that the collection can be searched with hybrid queries
+    pub sparse_vectors: bool,
+    /// Applied to each chunk's text before it is embedded. The payload keeps the original text
+    pub preprocessor: TextPreprocessor,
+}
+
+impl Default for EmbeddingOptions {
+    fn default() -> Self {
+        EmbeddingOptions {
+            embed_fields: vec![],
+            id_strategy: IdStrategy::ContentHash,
+            max_tokens: 8191,
+            overlap: 100,
+            embedding_timeout: Duration::from_secs(30),
+            normalize: false,
+            max_payload_bytes: 1_048_576,
+            oversize_behavior: OversizeBehavior::Truncate,
+            max_concurrent_embeddings: 8,
+            embedding_retry_policy: RetryPolicy::new(3, 200),
+            sparse_vectors: false,
+            preprocessor: TextPreprocessor::default(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Controls how many times a transient Qdrant failure is retried and how long to wait in between.
+/// The delay doubles after every attempt, starting at `base_delay_ms`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay_ms: 100,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_delay_ms: u64) -> Self {
+        RetryPolicy {
+            max_retries,
+            base_delay_ms,
+        }
+    }
+
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let multiplier = 2u64.saturating_pow(attempt);
+        Duration::from_millis(self.base_delay_ms.saturating_mul(multiplier))
+    }
+
+    /// A random delay between zero and `delay_for_attempt` ("full jitter"), which spreads out
+    /// retries from callers that failed at the same time
+    pub fn jittered_delay_for_attempt(&self, attempt: u32) -> Duration {
+        let max_delay_ms = self.delay_for_attempt(attempt).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay_ms))
+    }
+}
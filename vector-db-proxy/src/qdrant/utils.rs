@@ -1,37 +1,293 @@
 use anyhow::{anyhow, Result};
 
-use crate::qdrant::models::{CreateDisposition, PointSearchResults};
+use crate::data::errors::ProcessError;
+use crate::init::env_variables::GLOBAL_DATA;
+use crate::llm::models::EmbeddingModels;
+use crate::qdrant::client::qdrant_config;
+use crate::llm::utils::embed_text;
+use crate::metrics::utils::{POINTS_UPSERTED, UPSERT_LATENCY};
+use crate::data::preprocessing::TextPreprocessor;
+use crate::data::sparse_encoding::encode_query;
+use crate::qdrant::helpers::{
+    point_id_to_string, point_source_index, reciprocal_rank_fusion, retry_on_transient_errors,
+    score_meets_threshold, tenant_collection_name,
+};
+use crate::qdrant::models::{
+    CreateDisposition, FailedPoint, HashMapValues, IngestProgress, PointSearchResults, RetryPolicy,
+    SearchHit, SnapshotInfo, StoredPoint, UpsertReport, DENSE_VECTOR_NAME, SPARSE_VECTOR_NAME,
+};
+use crate::reranking::traits::Reranker;
 use crate::routes::models::FilterConditions;
-use crate::utils::conversions::convert_hashmap_to_filters;
+use crate::utils::conversions::{
+    convert_hashmap_to_filters, convert_hashmap_values_to_conditions, convert_string_to_distance,
+    convert_string_to_payload_indexes,
+};
+use crate::vector_store::traits::VectorStore;
+use async_trait::async_trait;
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::*;
+use qdrant_client::qdrant::value::Kind;
+use qdrant_client::qdrant::vectors::VectorsOptions;
 use qdrant_client::qdrant::vectors_config::Config;
 use qdrant_client::qdrant::{
-    CreateCollection, Filter, PointId, PointStruct, RecommendPoints, ScoredPoint, VectorParams,
-    VectorParamsMap, VectorsConfig,
+    Condition, CountPoints, CreateCollection, FieldType, Filter, PointId, PointStruct,
+    PointsSelector, RecommendPoints, ScoredPoint, ScrollPoints, SparseIndices, SparseVectorConfig,
+    SparseVectorParams, VectorParams, VectorParamsMap, VectorsConfig,
 };
-use std::sync::Arc;
+use mongodb::Database;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::RwLock;
+use tracing::info;
 use backoff::{ExponentialBackoff};
 use backoff::backoff::Backoff;
 
+// Upper bound on the number of hits a single search can return
+const MAX_SEARCH_LIMIT: u64 = 100;
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// Reciprocal rank fusion damping constant, 60 being the value from the original paper
+const RRF_K: f32 = 60.0;
+
+/// Collection name and, for collections with named vectors, the vector name
+type VectorKey = (String, Option<String>);
+
+/// Vector parameters (size and distance metric) of each collection (and named vector), looked up
+/// once per process. Wrappers are created per message so the cache can't live on the wrapper
+/// itself
+static COLLECTION_VECTOR_PARAMS: Lazy<Mutex<HashMap<VectorKey, VectorParams>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether each collection was created for hybrid search, cached like `COLLECTION_VECTOR_PARAMS`
+static COLLECTION_HYBRID: Lazy<Mutex<HashMap<String, bool>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Query text along with its dense embedding, ready to be searched for
+struct EmbeddedQuery {
+    text: String,
+    vector: Vec<f32>,
+    vector_name: Option<String>,
+}
+
+fn has_sparse_vector(point: &PointStruct) -> bool {
+    match point.vectors.as_ref().and_then(|v| v.vectors_options.as_ref()) {
+        Some(VectorsOptions::Vectors(named_vectors)) => {
+            named_vectors.vectors.values().any(|vector| vector.indices.is_some())
+        }
+        _ => false,
+    }
+}
+
 pub struct Qdrant {
-    client: Arc<RwLock<QdrantClient>>,
+    client: Arc<QdrantClient>,
     collection_name: String,
+    datasource_id: String,
+    retry_policy: RetryPolicy,
+    upsert_batch_size: usize,
+    embedding_context: Option<(Arc<RwLock<Database>>, EmbeddingModels)>,
+    distance: Distance,
+    payload_indexes: Vec<(String, FieldType)>,
+    rest_uri: String,
+    api_key: Option<String>,
+    snapshots_path: String,
+    reranker: Option<(Arc<dyn Reranker>, u64)>,
+    progress: Option<UnboundedSender<IngestProgress>>,
+    hybrid: bool,
+    text_preprocessor: TextPreprocessor,
+    collection_ensured: AtomicBool,
 }
 
 impl Qdrant {
-    pub fn new(client: Arc<RwLock<QdrantClient>>, collection_name: String) -> Self {
+    /// `QdrantClient` multiplexes requests over its own gRPC channel pool, so a single client can
+    /// be shared between any number of wrappers and upserts from concurrent messages run in
+    /// parallel without any locking
+    pub fn new(client: Arc<QdrantClient>, collection_name: String) -> Self {
         Qdrant {
             client,
+            datasource_id: collection_name.clone(),
             collection_name,
+            retry_policy: RetryPolicy::default(),
+            upsert_batch_size: 256,
+            embedding_context: None,
+            distance: Distance::Cosine,
+            // Every query is scoped by datasource so that field is always indexed
+            payload_indexes: vec![("datasource_id".to_string(), FieldType::Keyword)],
+            rest_uri: "http://localhost:6333".to_string(),
+            api_key: None,
+            snapshots_path: "/qdrant/snapshots".to_string(),
+            reranker: None,
+            progress: None,
+            hybrid: false,
+            text_preprocessor: TextPreprocessor::default(),
+            collection_ensured: AtomicBool::new(false),
+        }
+    }
+
+    /// Builds a wrapper configured from the environment (retry policy, upsert batch size and
+    /// distance metric), scoped to `tenant_id` if there is one (see `with_tenant` and
+    /// `datasource_tenant`)
+    pub async fn from_global_data(
+        client: Arc<QdrantClient>,
+        collection_name: String,
+        tenant_id: Option<String>,
+    ) -> Self {
+        let global_data = GLOBAL_DATA.read().await;
+        let retry_policy = RetryPolicy::new(
+            global_data.qdrant_max_retries,
+            global_data.qdrant_base_delay_ms,
+        );
+        Qdrant::new(client, collection_name)
+            .with_retry_policy(retry_policy)
+            .with_upsert_batch_size(global_data.qdrant_upsert_batch_size)
+            .with_distance(convert_string_to_distance(&global_data.qdrant_distance))
+            .with_payload_indexes(convert_string_to_payload_indexes(
+                &global_data.qdrant_payload_indexes,
+            ))
+            .with_rest_uri(global_data.qdrant_rest_uri.clone())
+            .with_api_key(qdrant_config(&global_data).api_key)
+            .with_snapshots_path(global_data.qdrant_snapshots_path.clone())
+            .with_text_preprocessor(TextPreprocessor::from(
+                global_data.text_preprocessing.as_str(),
+            ))
+            .with_tenant(tenant_id)
+    }
+
+    /// Scopes the wrapper to a tenant by naming its collection `{tenant_id}__{datasource_id}`, so
+    /// that upserts, searches, counts and deletes never touch another tenant's data. Without a
+    /// tenant the collection is named after the datasource alone
+    pub fn with_tenant(mut self, tenant_id: Option<String>) -> Self {
+        self.collection_name = tenant_collection_name(tenant_id.as_deref(), &self.datasource_id);
+        self
+    }
+
+    /// Overrides the policy used to retry transient errors during bulk upserts
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the number of points sent to Qdrant per upsert request during bulk upserts
+    pub fn with_upsert_batch_size(mut self, upsert_batch_size: usize) -> Self {
+        self.upsert_batch_size = upsert_batch_size.max(1);
+        self
+    }
+
+    /// Sets the embedding model used to embed query text in `search`. This should be the same
+    /// model the datasource was ingested with
+    pub fn with_embedding_model(
+        mut self,
+        mongo_conn: Arc<RwLock<Database>>,
+        embedding_model: EmbeddingModels,
+    ) -> Self {
+        self.embedding_context = Some((mongo_conn, embedding_model));
+        self
+    }
+
+    /// Sets the distance metric used when this wrapper has to create the collection
+    pub fn with_distance(mut self, distance: Distance) -> Self {
+        self.distance = distance;
+        self
+    }
+
+    /// Adds payload fields to index when the collection is ensured, on top of `datasource_id`
+    pub fn with_payload_indexes(mut self, payload_indexes: Vec<(String, FieldType)>) -> Self {
+        for (field, field_type) in payload_indexes {
+            match self.payload_indexes.iter_mut().find(|(f, _)| *f == field) {
+                Some(existing) => existing.1 = field_type,
+                None => self.payload_indexes.push((field, field_type)),
+            }
+        }
+        self
+    }
+
+    /// Reranks `search` results with `reranker`. The top `candidates` points by vector similarity
+    /// are reranked before truncating to the requested limit
+    pub fn with_reranker(mut self, reranker: Arc<dyn Reranker>, candidates: u64) -> Self {
+        self.reranker = Some((reranker, candidates));
+        self
+    }
+
+    /// Reports progress after every upsert chunk of `bulk_upsert_data`. Sending never blocks and
+    /// upserts carry on if the receiver has been dropped
+    pub fn with_progress(mut self, progress: UnboundedSender<IngestProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Marks the collection as holding both a `DENSE_VECTOR_NAME` and a `SPARSE_VECTOR_NAME`
+    /// vector per point. The collection is created with both and `search` runs a hybrid query,
+    /// fusing the dense and sparse rankings
+    pub fn with_hybrid_search(mut self, hybrid: bool) -> Self {
+        self.hybrid = hybrid;
+        self
+    }
+
+    /// Sets the preprocessing applied to query text in `search` before it is embedded. This
+    /// should match what the datasource's text was preprocessed with when it was ingested
+    pub fn with_text_preprocessor(mut self, text_preprocessor: TextPreprocessor) -> Self {
+        self.text_preprocessor = text_preprocessor;
+        self
+    }
+
+    /// Sets the url of Qdrant's REST API, used for operations the gRPC API doesn't offer such as
+    /// restoring snapshots
+    pub fn with_rest_uri(mut self, rest_uri: String) -> Self {
+        self.rest_uri = rest_uri.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Sets the api key sent with REST requests, the gRPC client carries its own
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Sets the directory Qdrant keeps its snapshots in, as seen from the Qdrant server
+    pub fn with_snapshots_path(mut self, snapshots_path: String) -> Self {
+        self.snapshots_path = snapshots_path.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Where Qdrant finds one of the collection's snapshots on its own disk
+    fn snapshot_location(&self, name: &str) -> String {
+        format!(
+            "file://{}/{}/{}",
+            self.snapshots_path, &self.collection_name, name
+        )
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// Calls Qdrant's health RPC to confirm it is reachable. Gives up after
+    /// `HEALTH_CHECK_TIMEOUT` so that a hung Qdrant doesn't hang readiness probes
+    ///
+    /// returns: Result<(), Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn health_check(&self) -> Result<()> {
+        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.client.health_check()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(anyhow!("Qdrant health check failed: {}", e)),
+            Err(_) => Err(anyhow!(
+                "Qdrant did not respond to the health check within {:?}",
+                HEALTH_CHECK_TIMEOUT
+            )),
         }
     }
 
     pub async fn get_list_of_collections(&self) -> Result<Vec<String>> {
         println!("Getting list of collection from DB...");
-        let qdrant_conn = &self.client.read().await;
+        let qdrant_conn = &self.client;
         let results = qdrant_conn.list_collections().await?;
         let list_of_collection: Vec<String> = results
             .collections
@@ -42,7 +298,7 @@ impl Qdrant {
     }
 
     pub async fn delete_collection(&self) -> Result<()> {
-        let qdrant_conn = &self.client.read().await;
+        let qdrant_conn = &self.client;
         match &self
             .check_collection_exists(CreateDisposition::CreateNever, None, None)
             .await
@@ -50,7 +306,11 @@ impl Qdrant {
             Ok(r) => match r {
                 true => match qdrant_conn.delete_collection(&self.collection_name).await {
                     Ok(result) => match result.result {
-                        true => Ok(()),
+                        true => {
+                            // The collection may be recreated with a different vector size or metric
+                            self.forget_vector_params();
+                            Ok(())
+                        }
                         false => Err(anyhow!("Collection could not be deleted!")),
                     },
                     Err(e) => Err(anyhow!(
@@ -72,6 +332,379 @@ impl Qdrant {
         }
     }
 
+    /// Drops the cached vector parameters of this wrapper's collection so that they are looked up
+    /// again the next time they are needed
+    fn forget_vector_params(&self) {
+        if let Ok(mut vector_params) = COLLECTION_VECTOR_PARAMS.lock() {
+            vector_params.retain(|(name, _), _| *name != self.collection_name);
+        }
+        if let Ok(mut hybrid) = COLLECTION_HYBRID.lock() {
+            hybrid.remove(&self.collection_name);
+        }
+    }
+
+    /// Filter matching every point that was ingested for this wrapper's datasource
+    fn datasource_filter(&self) -> Filter {
+        Filter::must([Condition::matches(
+            "datasource_id",
+            self.datasource_id.clone(),
+        )])
+    }
+
+    /// Snapshots this wrapper's collection, e.g. as a backup before re-ingesting a datasource
+    ///
+    /// returns: Result<SnapshotInfo, Error> an error if the collection does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn create_snapshot(&self) -> Result<SnapshotInfo> {
+        let qdrant_conn = &self.client;
+        if !qdrant_conn.collection_exists(&self.collection_name).await? {
+            return Err(anyhow!(
+                "Could not snapshot collection: {} as it does not exist",
+                &self.collection_name
+            ));
+        }
+        let response = qdrant_conn.create_snapshot(&self.collection_name).await?;
+        match response.snapshot_description {
+            Some(description) => {
+                let snapshot = SnapshotInfo::from(description);
+                println!(
+                    "Created snapshot: {} for collection: {}",
+                    snapshot.name, &self.collection_name
+                );
+                Ok(snapshot)
+            }
+            None => Err(anyhow!(
+                "Qdrant did not return a description for the snapshot of collection: {}",
+                &self.collection_name
+            )),
+        }
+    }
+
+    /// Lists the snapshots of this wrapper's collection
+    ///
+    /// returns: Result<Vec<SnapshotInfo>, Error> an error if the collection does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        let qdrant_conn = &self.client;
+        if !qdrant_conn.collection_exists(&self.collection_name).await? {
+            return Err(anyhow!(
+                "Could not list snapshots of collection: {} as it does not exist",
+                &self.collection_name
+            ));
+        }
+        let response = qdrant_conn.list_snapshots(&self.collection_name).await?;
+        Ok(response
+            .snapshot_descriptions
+            .into_iter()
+            .map(SnapshotInfo::from)
+            .collect())
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Name of a snapshot previously created by `create_snapshot`. The collection's
+    /// current points are replaced by the ones in the snapshot
+    ///
+    /// returns: Result<(), Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn restore_snapshot(&self, name: String) -> Result<()> {
+        if !self
+            .list_snapshots()
+            .await?
+            .iter()
+            .any(|snapshot| snapshot.name == name)
+        {
+            return Err(anyhow!(
+                "Snapshot: {} does not exist for collection: {}",
+                name,
+                &self.collection_name
+            ));
+        }
+        // The gRPC API has no recover endpoint, so Qdrant is asked over REST to recover from the
+        // snapshot file it wrote to its own storage
+        let collection_url = format!("{}/collections/{}", self.rest_uri, &self.collection_name);
+        let body = json!({
+            "location": self.snapshot_location(&name),
+            "priority": "snapshot"
+        });
+        let mut request = reqwest::Client::new()
+            .put(format!("{}/snapshots/recover?wait=true", collection_url))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("api-key", api_key);
+        }
+        let res = request.send().await?;
+        if res.status().is_success() {
+            // The snapshot may have been taken with a different vector size or metric
+            self.forget_vector_params();
+            info!(
+                collection = %self.collection_name,
+                snapshot = %name,
+                "Restored collection from snapshot"
+            );
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Failed to restore collection: {} from snapshot: {}. Status: {}",
+                &self.collection_name,
+                name,
+                res.status()
+            ))
+        }
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `exact`: Whether Qdrant should count every matching point or return an estimate, which
+    /// is cheaper on large collections
+    ///
+    /// returns: Result<u64, Error> the number of points belonging to this wrapper's datasource, 0
+    /// if the collection does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn count(&self, exact: bool) -> Result<u64> {
+        let qdrant_conn = &self.client;
+        if !qdrant_conn.collection_exists(&self.collection_name).await? {
+            return Ok(0);
+        }
+        let count_result = qdrant_conn
+            .count(&CountPoints {
+                collection_name: self.collection_name.to_owned(),
+                filter: Some(self.datasource_filter()),
+                exact: Some(exact),
+                ..Default::default()
+            })
+            .await?;
+        Ok(count_result.result.map_or(0, |r| r.count))
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor`: Where to resume from, the cursor returned by the previous call. None starts
+    /// from the beginning
+    /// * `limit`: Maximum number of points to return
+    ///
+    /// Pages through the points belonging to this wrapper's datasource in id order, without their
+    /// vectors. Points upserted while scrolling may or may not be visited
+    ///
+    /// returns: Result<(Vec<StoredPoint>, Option<PointId>), Error> a page of points and the cursor
+    /// of the next page, None once every point has been visited or if the collection does not
+    /// exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn scroll(
+        &self,
+        cursor: Option<PointId>,
+        limit: u32,
+    ) -> Result<(Vec<StoredPoint>, Option<PointId>)> {
+        self.scroll_points(cursor, limit, false).await
+    }
+
+    /// Like `scroll` but each point comes with its dense vector, e.g. to copy it to another
+    /// collection without re-embedding it
+    pub async fn scroll_with_vectors(
+        &self,
+        cursor: Option<PointId>,
+        limit: u32,
+    ) -> Result<(Vec<StoredPoint>, Option<PointId>)> {
+        self.scroll_points(cursor, limit, true).await
+    }
+
+    async fn scroll_points(
+        &self,
+        cursor: Option<PointId>,
+        limit: u32,
+        with_vectors: bool,
+    ) -> Result<(Vec<StoredPoint>, Option<PointId>)> {
+        let qdrant_conn = &self.client;
+        if !qdrant_conn.collection_exists(&self.collection_name).await? {
+            return Ok((vec![], None));
+        }
+        let scroll_request = ScrollPoints {
+            collection_name: self.collection_name.to_owned(),
+            filter: Some(self.datasource_filter()),
+            offset: cursor,
+            limit: Some(limit.max(1)),
+            with_payload: Some(true.into()),
+            with_vectors: Some(with_vectors.into()),
+            ..Default::default()
+        };
+        let scroll_result = retry_on_transient_errors(&self.retry_policy, || {
+            qdrant_conn.scroll(&scroll_request)
+        })
+            .await?;
+        let points = scroll_result
+            .result
+            .into_iter()
+            .map(StoredPoint::from)
+            .collect();
+        Ok((points, scroll_result.next_page_offset))
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// Deletes all points whose `datasource_id` payload field matches this wrapper's datasource.
+    /// If the collection does not exist there is nothing to delete and `Ok(0)` is returned
+    ///
+    /// returns: Result<u64, Error> the number of points deleted
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn delete_by_datasource(&self) -> Result<u64> {
+        self.delete_by_filter(HashMap::new()).await
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `filter`: Payload fields that deleted points must be equal to, e.g. a `doc_id`. The
+    /// conditions are always combined with the datasource scope, so an empty filter deletes the
+    /// whole datasource and never anything outside of it
+    ///
+    /// returns: Result<u64, Error> the number of points deleted, 0 if the collection does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn delete_by_filter(&self, filter: HashMap<String, HashMapValues>) -> Result<u64> {
+        let qdrant_conn = &self.client;
+        if !qdrant_conn.collection_exists(&self.collection_name).await? {
+            println!(
+                "Collection: {} does not exist, no points to delete",
+                &self.collection_name
+            );
+            return Ok(0);
+        }
+        let mut delete_filter = self.datasource_filter();
+        delete_filter
+            .must
+            .extend(convert_hashmap_values_to_conditions(&filter)?);
+        let count_result = qdrant_conn
+            .count(&CountPoints {
+                collection_name: self.collection_name.to_owned(),
+                filter: Some(delete_filter.clone()),
+                exact: Some(true),
+                ..Default::default()
+            })
+            .await?;
+        let points_to_delete = count_result.result.map_or(0, |r| r.count);
+        if points_to_delete == 0 {
+            return Ok(0);
+        }
+        match qdrant_conn
+            .delete_points_blocking(
+                &self.collection_name,
+                None,
+                &PointsSelector::from(delete_filter),
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                println!(
+                    "Deleted {} points for datasource: {}",
+                    points_to_delete, &self.datasource_id
+                );
+                Ok(points_to_delete)
+            }
+            Err(e) => Err(anyhow!(
+                "An error occurred while deleting points for datasource {}. Error: {}",
+                &self.datasource_id,
+                e
+            )),
+        }
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `point_ids`: IDs of the points to update, e.g. derived from `generate_point_id`
+    /// * `payload`: Payload fields to set. Existing fields not mentioned are left as is and the
+    /// vectors are untouched. `datasource_id` can not be overwritten
+    ///
+    /// returns: Result<(), Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn update_payload(
+        &self,
+        point_ids: Vec<PointId>,
+        payload: HashMap<String, HashMapValues>,
+    ) -> Result<()> {
+        if point_ids.is_empty() || payload.is_empty() {
+            return Ok(());
+        }
+        let payload: HashMap<String, serde_json::Value> = payload
+            .into_iter()
+            .filter(|(k, _)| k != "datasource_id")
+            .map(|(k, v)| (k, serde_json::Value::from(v)))
+            .collect();
+        let Ok(payload) = Payload::try_from(json!(payload)) else {
+            return Err(anyhow!("Could not convert payload to JSON type"));
+        };
+        let qdrant_conn = &self.client;
+        let points_selector = PointsSelector::from(point_ids);
+        let res = retry_on_transient_errors(&self.retry_policy, || {
+            qdrant_conn.set_payload_blocking(
+                &self.collection_name,
+                None,
+                &points_selector,
+                payload.clone(),
+                None,
+                None,
+            )
+        })
+            .await?;
+        match res.result {
+            Some(update_result) if update_result.status == 2 => Ok(()),
+            _ => Err(anyhow!(
+                "Payload update for datasource {} did not complete",
+                &self.datasource_id
+            )),
+        }
+    }
+
     ///
     ///
     /// # Arguments
@@ -92,12 +725,32 @@ impl Qdrant {
         create_disposition: CreateDisposition,
         vector_length: Option<u64>,
         vector_name: Option<String>,
+    ) -> Result<bool> {
+        self.check_collection_exists_with_distance(
+            create_disposition,
+            vector_length,
+            vector_name,
+            self.distance,
+            self.hybrid,
+        )
+            .await
+    }
+
+    /// With `sparse` the collection is created with a `SPARSE_VECTOR_NAME` sparse vector next to
+    /// its dense vector, which is then always named (`DENSE_VECTOR_NAME` if no name is given)
+    async fn check_collection_exists_with_distance(
+        &self,
+        create_disposition: CreateDisposition,
+        vector_length: Option<u64>,
+        vector_name: Option<String>,
+        distance: Distance,
+        sparse: bool,
     ) -> Result<bool> {
         println!(
             "Checking if Collection: {} exists...",
             &self.collection_name
         );
-        let qdrant_client = &self.client.read().await;
+        let qdrant_client = &self.client;
         let list_of_collections = qdrant_client.list_collections().await?;
         let results = list_of_collections
             .collections
@@ -113,6 +766,13 @@ impl Qdrant {
             );
             let vector_size = vector_length.unwrap_or(512); // Default to fastembed embedding size if none is given;
             let mut config: Option<VectorsConfig> = Some(VectorsConfig::default());
+            let vector_name = match vector_name {
+                None if sparse => Some(DENSE_VECTOR_NAME.to_string()),
+                vector_name => vector_name,
+            };
+            let sparse_config = sparse.then(|| SparseVectorConfig {
+                map: [(SPARSE_VECTOR_NAME.to_string(), SparseVectorParams::default())].into(),
+            });
             match create_disposition {
                 CreateDisposition::CreateIfNeeded => {
                     // check if vector name is a value or None
@@ -127,7 +787,7 @@ impl Qdrant {
                                         String::from(model_name.as_str()),
                                         VectorParams {
                                             size: vector_size, // This is the number of dimensions in the collection (basically the number of columns)
-                                            distance: Distance::Cosine.into(), // The distance metric we will use in this collection
+                                            distance: distance.into(), // The distance metric we will use in this collection
                                             ..Default::default()
                                         },
                                     )]
@@ -139,7 +799,7 @@ impl Qdrant {
                             config = Some(VectorsConfig {
                                 config: Some(Config::Params(VectorParams {
                                     size: vector_size, // This is the number of dimensions in the collection (basically the number of columns)
-                                    distance: Distance::Cosine.into(), // The distance metric we will use in this collection
+                                    distance: distance.into(), // The distance metric we will use in this collection
                                     ..Default::default()
                                 })),
                             });
@@ -149,6 +809,7 @@ impl Qdrant {
                         .create_collection(&CreateCollection {
                             collection_name: self.collection_name.to_owned(),
                             vectors_config: config,
+                            sparse_vectors_config: sparse_config,
                             ..Default::default()
                         })
                         .await
@@ -183,6 +844,66 @@ impl Qdrant {
         }
     }
 
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `vector_size`: Number of dimensions of the vectors stored in the collection
+    /// * `vector_name`: Name of the vector if the collection uses named vectors
+    /// * `distance`: Distance metric to create the collection with
+    ///
+    /// Creates the collection if it does not exist yet. If it already exists this is a no-op.
+    /// Once the collection has been confirmed subsequent calls return straight away
+    ///
+    /// returns: Result<(), Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn ensure_collection(
+        &self,
+        vector_size: u64,
+        vector_name: Option<String>,
+        distance: Distance,
+    ) -> Result<()> {
+        self.ensure_collection_with_sparse(vector_size, vector_name, distance, self.hybrid)
+            .await
+    }
+
+    async fn ensure_collection_with_sparse(
+        &self,
+        vector_size: u64,
+        vector_name: Option<String>,
+        distance: Distance,
+        sparse: bool,
+    ) -> Result<()> {
+        if self.collection_ensured.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        match self
+            .check_collection_exists_with_distance(
+                CreateDisposition::CreateIfNeeded,
+                Some(vector_size),
+                vector_name,
+                distance,
+                sparse,
+            )
+            .await?
+        {
+            true => {
+                self.ensure_payload_indexes().await?;
+                self.collection_ensured.store(true, Ordering::Release);
+                Ok(())
+            }
+            false => Err(anyhow!(
+                "Collection: {} could not be created",
+                &self.collection_name
+            )),
+        }
+    }
+
     ///
     ///
     /// # Arguments
@@ -201,7 +922,7 @@ impl Qdrant {
             "Uploading data point to collection: {}",
             &self.collection_name
         );
-        let qdrant_conn = &self.client.read().await;
+        let qdrant_conn = &self.client;
         let upsert_results = qdrant_conn
             .upsert_points(&self.collection_name, None, vec![point], None)
             .await?;
@@ -221,7 +942,7 @@ impl Qdrant {
             "Uploading data point to collection: {}",
             &self.collection_name
         );
-        let qdrant_conn = &self.client.read().await;
+        let qdrant_conn = &self.client;
         match &self
             .check_collection_exists(
                 CreateDisposition::CreateIfNeeded,
@@ -293,79 +1014,293 @@ impl Qdrant {
         }
     }
 
+    /// Creates an index for each of the wrapper's payload index fields that the collection
+    /// doesn't have one for yet, so calling this repeatedly is harmless
+    ///
+    /// returns: Result<(), Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn ensure_payload_indexes(&self) -> Result<()> {
+        let qdrant_conn = &self.client;
+        let collection_info = qdrant_conn.collection_info(&self.collection_name).await?;
+        let existing_indexes: HashSet<String> = collection_info
+            .result
+            .map(|info| info.payload_schema.into_keys().collect())
+            .unwrap_or_default();
+        for (field, field_type) in &self.payload_indexes {
+            if existing_indexes.contains(field) {
+                continue;
+            }
+            retry_on_transient_errors(&self.retry_policy, || {
+                qdrant_conn.create_field_index_blocking(
+                    &self.collection_name,
+                    field,
+                    *field_type,
+                    None,
+                    None,
+                )
+            })
+                .await?;
+        }
+        Ok(())
+    }
+
     ///
     ///
     /// # Arguments
     ///
-    /// * `points`:
+    /// * `points`: PointStructs to upload to Qdrant. Points are upserted in chunks of
+    /// `upsert_batch_size`. Transient errors are retried according to the wrapper's `RetryPolicy`,
+    /// if a chunk still fails its points are upserted one at a time so that only the offending
+    /// points are reported as failed
     ///
-    /// returns: Result<bool, Error>
+    /// returns: Result<UpsertReport, Error>
     ///
     /// # Examples
     ///
     /// ```
     ///
     /// ```
+    #[tracing::instrument(
+        skip_all,
+        fields(datasource_id = %self.datasource_id, point_count = points.len())
+    )]
     pub async fn bulk_upsert_data(
         &self,
         points: Vec<PointStruct>,
         vector_length: Option<u64>,
         vector_name: Option<String>,
-    ) -> Result<bool> {
+    ) -> Result<UpsertReport> {
         println!(
             "Uploading bulk data points to collection: {}",
             &self.collection_name
         );
-        let qdrant_conn = &self.client.read().await;
-        match &self
-            .check_collection_exists(
-                CreateDisposition::CreateIfNeeded,
-                vector_length,
-                vector_name,
-            )
+        let vector_size = vector_length.unwrap_or(512); // Default to fastembed embedding size if none is given
+        let vector_name_for_check = vector_name.clone();
+        // Points from a datasource that opted into hybrid search carry a sparse vector, which
+        // the collection has to be created for
+        let sparse = self.hybrid || points.iter().any(has_sparse_vector);
+        if let Err(e) = self
+            .ensure_collection_with_sparse(vector_size, vector_name, self.distance, sparse)
             .await
         {
-            Ok(result) => match result {
-                true => {
-                    match qdrant_conn
-                        .upsert_points_batch_blocking(
-                            &self.collection_name,
-                            None,
-                            points,
-                            None,
-                            100,
-                        )
-                        .await
-                    {
-                        Ok(res) => match res.result {
-                            Some(stat) => match stat.status {
-                                2 => {
-                                    println!("upload success");
-                                    Ok(true)
-                                }
-                                _ => {
-                                    println!("Upload failed");
-                                    Ok(false)
-                                }
-                            },
-                            None => Err(anyhow!("Results returned None")),
-                        },
-                        Err(e) => Err(anyhow!("There was an error upserting to qdrant: {}", e)),
+            println!("Err: {}", e);
+            return Err(anyhow!(
+                "An error occurred while trying to create collection: {}",
+                e
+            ));
+        }
+        self.check_vector_dimensions(&points, vector_name_for_check.as_deref())
+            .await?;
+        let total = points.len();
+        let mut report = UpsertReport::default();
+        let upsert_timer = UPSERT_LATENCY
+            .with_label_values(&[&self.datasource_id])
+            .start_timer();
+        // Upsert sequentially in chunks so that large payloads don't exceed the gRPC message size
+        for (chunk_index, chunk) in points.chunks(self.upsert_batch_size).enumerate() {
+            match self.upsert_chunk(chunk).await {
+                Ok(_) => report.succeeded += chunk.len(),
+                Err(e) if chunk.len() == 1 => report.failed.push(FailedPoint {
+                    id: point_id_to_string(&chunk[0].id),
+                    source_index: point_source_index(&chunk[0]),
+                    reason: e.to_string(),
+                }),
+                Err(e) => {
+                    println!(
+                        "There was an error upserting chunk {} to qdrant: {}. Retrying points individually",
+                        chunk_index, e
+                    );
+                    for point in chunk {
+                        match self.upsert_chunk(std::slice::from_ref(point)).await {
+                            Ok(_) => report.succeeded += 1,
+                            Err(e) => report.failed.push(FailedPoint {
+                                id: point_id_to_string(&point.id),
+                                source_index: point_source_index(point),
+                                reason: e.to_string(),
+                            }),
+                        }
                     }
                 }
-                false => {
-                    println!("Collection: {} creation failed!", &self.collection_name);
-                    Err(anyhow!("Collection does not exist"))
+            }
+            if let Some(progress) = &self.progress {
+                let _ = progress.send(IngestProgress {
+                    processed: report.succeeded + report.failed.len(),
+                    total,
+                });
+            }
+        }
+        upsert_timer.observe_duration();
+        POINTS_UPSERTED
+            .with_label_values(&[&self.datasource_id])
+            .inc_by(report.succeeded as u64);
+        println!("Uploaded {} out of {} points", report.succeeded, total);
+        Ok(report)
+    }
+
+    /// Deletes the points with these ids, in chunks of `upsert_batch_size`
+    async fn delete_point_ids(&self, ids: &[PointId]) -> Result<()> {
+        let qdrant_conn = &self.client;
+        for chunk in ids.chunks(self.upsert_batch_size) {
+            let points_selector = PointsSelector::from(chunk.to_vec());
+            retry_on_transient_errors(&self.retry_policy, || {
+                qdrant_conn.delete_points_blocking(
+                    &self.collection_name,
+                    None,
+                    &points_selector,
+                    None,
+                )
+            })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Ids of every point belonging to this wrapper's datasource
+    async fn datasource_point_ids(&self) -> Result<Vec<PointId>> {
+        let qdrant_conn = &self.client;
+        let mut ids = vec![];
+        let mut cursor = None;
+        loop {
+            let scroll_request = ScrollPoints {
+                collection_name: self.collection_name.to_owned(),
+                filter: Some(self.datasource_filter()),
+                offset: cursor,
+                limit: Some(self.upsert_batch_size.max(1) as u32),
+                with_payload: Some(false.into()),
+                with_vectors: Some(false.into()),
+                ..Default::default()
+            };
+            let scroll_result = retry_on_transient_errors(&self.retry_policy, || {
+                qdrant_conn.scroll(&scroll_request)
+            })
+                .await?;
+            ids.extend(scroll_result.result.into_iter().filter_map(|point| point.id));
+            cursor = scroll_result.next_page_offset;
+            if cursor.is_none() {
+                return Ok(ids);
+            }
+        }
+    }
+
+    /// The configured parameters of the collection's vectors (or of the named vector), cached in
+    /// `COLLECTION_VECTOR_PARAMS`. None if the collection doesn't define it
+    async fn collection_vector_params(
+        &self,
+        vector_name: Option<&str>,
+    ) -> Result<Option<VectorParams>> {
+        let cache_key = (self.collection_name.clone(), vector_name.map(str::to_string));
+        if let Some(params) = COLLECTION_VECTOR_PARAMS
+            .lock()
+            .ok()
+            .and_then(|vector_params| vector_params.get(&cache_key).cloned())
+        {
+            return Ok(Some(params));
+        }
+        let collection_info = self.client.collection_info(&self.collection_name).await?;
+        let vectors_config = collection_info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.vectors_config)
+            .and_then(|vectors_config| vectors_config.config);
+        let params = match (vectors_config, vector_name) {
+            (Some(Config::Params(params)), _) => Some(params),
+            (Some(Config::ParamsMap(mut params_map)), Some(name)) => params_map.map.remove(name),
+            _ => None,
+        };
+        if let (Some(params), Ok(mut vector_params)) = (&params, COLLECTION_VECTOR_PARAMS.lock()) {
+            vector_params.insert(cache_key, params.clone());
+        }
+        Ok(params)
+    }
+
+    /// Whether the collection was created for hybrid search, with a `SPARSE_VECTOR_NAME` sparse
+    /// vector next to its dense one. Cached in `COLLECTION_HYBRID`
+    async fn collection_is_hybrid(&self) -> Result<bool> {
+        if let Some(hybrid) = COLLECTION_HYBRID
+            .lock()
+            .ok()
+            .and_then(|hybrid| hybrid.get(&self.collection_name).copied())
+        {
+            return Ok(hybrid);
+        }
+        let collection_info = self.client.collection_info(&self.collection_name).await?;
+        let hybrid = collection_info
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.sparse_vectors_config)
+            .is_some_and(|sparse_config| sparse_config.map.contains_key(SPARSE_VECTOR_NAME));
+        if let Ok(mut collection_hybrid) = COLLECTION_HYBRID.lock() {
+            collection_hybrid.insert(self.collection_name.clone(), hybrid);
+        }
+        Ok(hybrid)
+    }
+
+    async fn collection_vector_size(&self, vector_name: Option<&str>) -> Result<Option<u64>> {
+        Ok(self
+            .collection_vector_params(vector_name)
+            .await?
+            .map(|params| params.size))
+    }
+
+    /// The distance metric the collection's vectors (or the named vector) were created with
+    pub async fn collection_distance(&self, vector_name: Option<&str>) -> Result<Option<Distance>> {
+        Ok(self
+            .collection_vector_params(vector_name)
+            .await?
+            .and_then(|params| Distance::from_i32(params.distance)))
+    }
+
+    /// Fails with `ProcessError::DimensionMismatch` if any point's vector doesn't have the size
+    /// the collection was created with, which Qdrant would otherwise reject with an opaque error
+    async fn check_vector_dimensions(
+        &self,
+        points: &[PointStruct],
+        vector_name: Option<&str>,
+    ) -> Result<()> {
+        let Some(expected) = self.collection_vector_size(vector_name).await? else {
+            return Ok(());
+        };
+        for point in points {
+            let dimension = match point.vectors.as_ref().and_then(|v| v.vectors_options.as_ref()) {
+                Some(VectorsOptions::Vector(vector)) => Some(vector.data.len()),
+                Some(VectorsOptions::Vectors(named_vectors)) => match vector_name {
+                    Some(name) => named_vectors.vectors.get(name).map(|v| v.data.len()),
+                    None => None,
+                },
+                None => None,
+            };
+            if let Some(got) = dimension {
+                if got as u64 != expected {
+                    return Err(anyhow!(ProcessError::DimensionMismatch {
+                        expected,
+                        got: got as u64,
+                    }));
                 }
-            },
-            Err(e) => {
-                println!("Err: {}", e);
-                Err(anyhow!(
-                    "An error occurred while trying to create collection: {}",
-                    e
-                ))
             }
         }
+        Ok(())
+    }
+
+    async fn upsert_chunk(&self, chunk: &[PointStruct]) -> Result<()> {
+        let qdrant_conn = &self.client;
+        let res = retry_on_transient_errors(&self.retry_policy, || {
+            qdrant_conn.upsert_points_blocking(&self.collection_name, None, chunk.to_vec(), None)
+        })
+            .await?;
+        match res.result {
+            Some(stat) => match stat.status {
+                2 => Ok(()),
+                status => Err(anyhow!("Upsert did not complete, status: {}", status)),
+            },
+            None => Err(anyhow!("Upsert results returned None")),
+        }
     }
 
     ///
@@ -389,7 +1324,7 @@ impl Qdrant {
         filters: Option<FilterConditions>,
         limit: Option<u64>,
     ) -> Result<Vec<PointSearchResults>> {
-        let qdrant_conn = &self.client.read().await;
+        let qdrant_conn = &self.client;
         let (must, must_not, should) = convert_hashmap_to_filters(&filters);
         let mut response_data: Vec<PointSearchResults> = vec![];
         let search_result = qdrant_conn
@@ -416,6 +1351,180 @@ impl Qdrant {
         Ok(response_data)
     }
 
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `query_text`: Text to embed and search for
+    /// * `limit`: The number of results to return from search. Capped at `MAX_SEARCH_LIMIT`
+    /// * `filter`: Payload fields that returned points must be equal to
+    /// * `min_score`: Minimum similarity for a hit to be returned. For distance metrics where
+    /// lower scores are better (euclidean, manhattan) it is a maximum distance instead. It only
+    /// applies to dense scores: dense hits are filtered before fusion and reranking, and fused or
+    /// rerank scores are never compared against it
+    ///
+    /// With hybrid search the query is run against both the dense and the sparse vectors and the
+    /// two rankings are fused, each hit's score being its fused score. When the wrapper has a
+    /// reranker the candidates are re-scored on their `page_content` and returned in rerank
+    /// order, otherwise hits are ordered by (fused) score. Hybrid search fails with
+    /// `ProcessError::HybridSearchUnsupported` against a dense only collection, and dense only
+    /// search with `ProcessError::HybridSearchRequired` against a hybrid one
+    ///
+    /// returns: Result<Vec<SearchHit, Global>, Error>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn search(
+        &self,
+        query_text: String,
+        limit: u64,
+        filter: Option<HashMap<String, HashMapValues>>,
+        min_score: Option<f32>,
+    ) -> Result<Vec<SearchHit>> {
+        let Some((mongo_conn, embedding_model)) = &self.embedding_context else {
+            return Err(anyhow!(
+                "No embedding model was set for collection: {}",
+                &self.collection_name
+            ));
+        };
+        // Queries go through the same preprocessing as the documents they are compared against
+        let embedding_text = self.text_preprocessor.apply(&query_text);
+        let embeddings = embed_text(
+            Arc::clone(mongo_conn),
+            self.datasource_id.clone(),
+            vec![&embedding_text],
+            embedding_model,
+        )
+            .await?;
+        let Some(vector) = embeddings.into_iter().next() else {
+            return Err(anyhow!("Embedding the query text returned no vectors"));
+        };
+        let query = EmbeddedQuery {
+            text: query_text,
+            vector,
+            vector_name: embedding_model.to_str().map(|m| m.to_string()),
+        };
+        self.search_embedded(query, limit, filter, min_score).await
+    }
+
+    /// Runs `search` for a query that has already been embedded
+    async fn search_embedded(
+        &self,
+        query: EmbeddedQuery,
+        limit: u64,
+        filter: Option<HashMap<String, HashMapValues>>,
+        min_score: Option<f32>,
+    ) -> Result<Vec<SearchHit>> {
+        // Hybrid queries need the sparse vector, and the dense vector of a hybrid collection is
+        // named `DENSE_VECTOR_NAME` rather than after the model, so the two can't be mixed
+        match (self.hybrid, self.collection_is_hybrid().await?) {
+            (true, false) => {
+                return Err(anyhow!(ProcessError::HybridSearchUnsupported(
+                    self.collection_name.clone()
+                )))
+            }
+            (false, true) => {
+                return Err(anyhow!(ProcessError::HybridSearchRequired(
+                    self.collection_name.clone()
+                )))
+            }
+            _ => {}
+        }
+        let mut search_filter = self.datasource_filter();
+        if let Some(payload_filter) = &filter {
+            search_filter
+                .must
+                .extend(convert_hashmap_values_to_conditions(payload_filter)?);
+        }
+        let candidate_limit = match &self.reranker {
+            Some((_, candidates)) => limit.max(*candidates).min(MAX_SEARCH_LIMIT),
+            None => limit.min(MAX_SEARCH_LIMIT),
+        };
+        let dense_vector_name = match self.hybrid {
+            true => Some(DENSE_VECTOR_NAME.to_string()),
+            false => query.vector_name,
+        };
+        let distance = match min_score {
+            Some(_) => self
+                .collection_distance(dense_vector_name.as_deref())
+                .await?
+                .unwrap_or(self.distance),
+            None => self.distance,
+        };
+        let meets_min_score = |point: &ScoredPoint| match min_score {
+            Some(min_score) => score_meets_threshold(distance, point.score, min_score),
+            None => true,
+        };
+        let dense_search = SearchPoints {
+            collection_name: self.collection_name.clone(),
+            vector: query.vector,
+            vector_name: dense_vector_name,
+            filter: Some(search_filter),
+            limit: candidate_limit,
+            with_payload: Some(true.into()),
+            ..Default::default()
+        };
+        let qdrant_conn = &self.client;
+        let points = match self.hybrid {
+            true => {
+                let sparse_vector = encode_query(&self.text_preprocessor.apply(&query.text));
+                let sparse_search = SearchPoints {
+                    vector: sparse_vector.values,
+                    sparse_indices: Some(SparseIndices {
+                        data: sparse_vector.indices,
+                    }),
+                    vector_name: Some(SPARSE_VECTOR_NAME.to_string()),
+                    ..dense_search.clone()
+                };
+                let (dense_result, sparse_result) = tokio::try_join!(
+                    qdrant_conn.search_points(&dense_search),
+                    qdrant_conn.search_points(&sparse_search)
+                )?;
+                let mut dense_points = dense_result.result;
+                dense_points.retain(meets_min_score);
+                reciprocal_rank_fusion(vec![dense_points, sparse_result.result], RRF_K)
+            }
+            false => {
+                let mut dense_points = qdrant_conn.search_points(&dense_search).await?.result;
+                dense_points.retain(meets_min_score);
+                dense_points
+            }
+        };
+        let mut hits: Vec<SearchHit> = points
+            .into_iter()
+            .map(|point| SearchHit {
+                score: point.score,
+                rerank_score: None,
+                payload: point.payload,
+            })
+            .collect();
+        if let Some((reranker, _)) = &self.reranker {
+            let documents: Vec<String> = hits
+                .iter()
+                .map(|hit| {
+                    match hit.payload.get("page_content").and_then(|v| v.kind.as_ref()) {
+                        Some(Kind::StringValue(text)) => text.clone(),
+                        _ => String::new(),
+                    }
+                })
+                .collect();
+            let scores = reranker.rerank(&query.text, &documents).await?;
+            for (hit, score) in hits.iter_mut().zip(scores) {
+                hit.rerank_score = Some(score);
+            }
+            hits.sort_by(|a, b| {
+                b.rerank_score
+                    .partial_cmp(&a.rerank_score)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+        hits.truncate(limit.min(MAX_SEARCH_LIMIT) as usize);
+        Ok(hits)
+    }
+
     ///
     ///
     /// # Arguments
@@ -441,7 +1550,7 @@ impl Qdrant {
         let point_id = PointId {
             point_id_options: Some(point_id::PointIdOptions::Uuid(id)),
         };
-        let qdrant = &self.client.read().await;
+        let qdrant = &self.client;
         let recommend = RecommendPoints {
             collection_name: self.collection_name.to_owned(),
             positive: vec![point_id],
@@ -465,3 +1574,481 @@ impl Qdrant {
         }
     }
 }
+
+#[async_trait]
+impl VectorStore for Qdrant {
+    fn datasource_id(&self) -> &str {
+        &self.datasource_id
+    }
+
+    async fn ensure_collection(&self, vector_size: u64, vector_name: Option<String>) -> Result<()> {
+        Qdrant::ensure_collection(self, vector_size, vector_name, self.distance).await
+    }
+
+    async fn bulk_upsert(
+        &self,
+        points: Vec<PointStruct>,
+        vector_length: Option<u64>,
+        vector_name: Option<String>,
+    ) -> Result<UpsertReport> {
+        self.bulk_upsert_data(points, vector_length, vector_name).await
+    }
+
+    async fn delete_by_datasource(&self) -> Result<u64> {
+        Qdrant::delete_by_datasource(self).await
+    }
+
+    async fn point_ids(&self) -> Result<Vec<PointId>> {
+        self.datasource_point_ids().await
+    }
+
+    async fn delete_points(&self, ids: Vec<PointId>) -> Result<()> {
+        self.delete_point_ids(&ids).await
+    }
+}
+
+// The `Qdrant` tests need a running Qdrant instance, e.g. `docker run -p 6334:6334 qdrant/qdrant`,
+// and are run with `cargo test -- --ignored`. Each test works in a fresh collection
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qdrant::helpers::generate_point_id;
+    use crate::data::sparse_encoding::encode_document;
+    use crate::llm::cache::embedding_cache;
+    use crate::qdrant::helpers::embed_table_chunks_async;
+    use crate::qdrant::models::{EmbeddingOptions, IdStrategy};
+    use qdrant_client::qdrant::Vector;
+    use uuid::Uuid;
+
+    const TEST_VECTOR: &str = "test";
+
+    fn test_client() -> Arc<QdrantClient> {
+        let url = std::env::var("QDRANT_TEST_URL").unwrap_or("http://localhost:6334".to_string());
+        Arc::new(QdrantClient::from_url(&url).build().unwrap())
+    }
+
+    fn test_wrapper() -> Qdrant {
+        Qdrant::new(test_client(), format!("test_{}", Uuid::new_v4().simple()))
+    }
+
+    fn test_point(
+        qdrant: &Qdrant,
+        id: u64,
+        mut payload: serde_json::Value,
+        vector: Vec<f32>,
+    ) -> PointStruct {
+        payload["datasource_id"] = json!(qdrant.datasource_id);
+        PointStruct::new(
+            id,
+            HashMap::from([(TEST_VECTOR.to_string(), vector)]),
+            Payload::try_from(payload).unwrap(),
+        )
+    }
+
+    async fn upsert(qdrant: &Qdrant, points: Vec<PointStruct>) {
+        let report = qdrant
+            .bulk_upsert_data(points, Some(4), Some(TEST_VECTOR.to_string()))
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn delete_by_datasource_removes_every_point() {
+        let qdrant = test_wrapper();
+        let points = (0..3)
+            .map(|i| test_point(&qdrant, i, json!({"row": i}), vec![i as f32, 1.0, 0.0, 0.0]))
+            .collect();
+        upsert(&qdrant, points).await;
+        assert_eq!(qdrant.count(true).await.unwrap(), 3);
+        assert_eq!(qdrant.delete_by_datasource().await.unwrap(), 3);
+        assert_eq!(qdrant.count(true).await.unwrap(), 0);
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn delete_by_datasource_without_a_collection_deletes_nothing() {
+        let qdrant = test_wrapper();
+        assert_eq!(qdrant.delete_by_datasource().await.unwrap(), 0);
+    }
+
+    fn test_query(vector: Vec<f32>) -> EmbeddedQuery {
+        EmbeddedQuery {
+            text: "query".to_string(),
+            vector,
+            vector_name: Some(TEST_VECTOR.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn search_returns_only_points_matching_the_filter() {
+        let qdrant = test_wrapper();
+        let points = (0..4)
+            .map(|i| {
+                let document_type = if i % 2 == 0 { "faq" } else { "manual" };
+                let fields = json!({"document_type": document_type});
+                test_point(&qdrant, i, fields, vec![1.0, i as f32, 0.0, 0.0])
+            })
+            .collect();
+        upsert(&qdrant, points).await;
+        let filter = HashMap::from([(
+            "document_type".to_string(),
+            HashMapValues::Str("faq".to_string()),
+        )]);
+        let hits = qdrant
+            .search_embedded(test_query(vec![1.0, 1.0, 0.0, 0.0]), 10, Some(filter), None)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 2);
+        for hit in hits {
+            assert_eq!(hit.payload["document_type"].as_str().unwrap(), "faq");
+        }
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn upserting_the_same_row_twice_leaves_one_point() {
+        let qdrant = test_wrapper();
+        let row = HashMap::from([
+            ("id".to_string(), HashMapValues::Int(1)),
+            ("status".to_string(), HashMapValues::Str("open".to_string())),
+        ]);
+        let point_id = generate_point_id(&qdrant.datasource_id, &row, &IdStrategy::ContentHash);
+        for _ in 0..2 {
+            let point = PointStruct::new(
+                point_id.clone(),
+                HashMap::from([(TEST_VECTOR.to_string(), vec![1.0, 0.0, 0.0, 0.0])]),
+                Payload::try_from(json!({"datasource_id": qdrant.datasource_id, "id": 1}))
+                    .unwrap(),
+            );
+            upsert(&qdrant, vec![point]).await;
+        }
+        assert_eq!(qdrant.count(true).await.unwrap(), 1);
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn ingesting_creates_the_payload_indexes() {
+        let qdrant =
+            test_wrapper().with_payload_indexes(vec![("row".to_string(), FieldType::Integer)]);
+        upsert(&qdrant, vec![test_point(&qdrant, 1, json!({"row": 1}), vec![1.0, 0.0, 0.0, 0.0])])
+            .await;
+        let collection_info = qdrant.client.collection_info(&qdrant.collection_name).await.unwrap();
+        let payload_schema = collection_info.result.unwrap().payload_schema;
+        assert!(payload_schema.contains_key("datasource_id"));
+        assert!(payload_schema.contains_key("row"));
+        // Indexes that already exist are left alone
+        qdrant.ensure_payload_indexes().await.unwrap();
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn update_payload_leaves_the_vector_untouched() {
+        let qdrant = test_wrapper();
+        let vector = vec![0.5, 0.5, 0.5, 0.5];
+        let point = test_point(&qdrant, 1, json!({"status": "draft", "row": 1}), vector.clone());
+        upsert(&qdrant, vec![point]).await;
+        let payload = HashMap::from([(
+            "status".to_string(),
+            HashMapValues::Str("published".to_string()),
+        )]);
+        qdrant.update_payload(vec![PointId::from(1)], payload).await.unwrap();
+        let (points, _) = qdrant.scroll_with_vectors(None, 10).await.unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].payload["status"].as_str().unwrap(), "published");
+        // Fields that weren't updated are kept
+        assert!(points[0].payload.contains_key("row"));
+        assert_eq!(points[0].vector, Some(vector));
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[test]
+    fn tenants_get_their_own_collection() {
+        let qdrant = Qdrant::new(test_client(), "ds".to_string());
+        assert_eq!(qdrant.collection_name, "ds");
+        let qdrant = qdrant.with_tenant(Some("org_a".to_string()));
+        assert_eq!(qdrant.collection_name, "org_a__ds");
+        // Points are still scoped by the datasource itself
+        assert_eq!(qdrant.datasource_id, "ds");
+    }
+
+    #[test]
+    fn snapshots_are_recovered_from_qdrant_local_storage() {
+        let qdrant = Qdrant::new(test_client(), "ds".to_string())
+            .with_tenant(Some("org_a".to_string()))
+            .with_snapshots_path("/data/snapshots/".to_string());
+        assert_eq!(
+            qdrant.snapshot_location("ds-1.snapshot"),
+            "file:///data/snapshots/org_a__ds/ds-1.snapshot"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn two_tenants_with_the_same_datasource_write_to_separate_collections() {
+        let datasource_id = format!("test_{}", Uuid::new_v4().simple());
+        let tenant = |tenant_id: &str| {
+            Qdrant::new(test_client(), datasource_id.clone())
+                .with_tenant(Some(tenant_id.to_string()))
+        };
+        let (tenant_a, tenant_b) = (tenant("org_a"), tenant("org_b"));
+        assert_ne!(tenant_a.collection_name, tenant_b.collection_name);
+        upsert(&tenant_a, vec![test_point(&tenant_a, 1, json!({}), vec![1.0, 0.0, 0.0, 0.0])])
+            .await;
+        let points = (1..=2)
+            .map(|i| test_point(&tenant_b, i, json!({}), vec![0.0, 1.0, 0.0, 0.0]))
+            .collect();
+        upsert(&tenant_b, points).await;
+        assert_eq!(tenant_a.count(true).await.unwrap(), 1);
+        assert_eq!(tenant_b.count(true).await.unwrap(), 2);
+        tenant_a.delete_collection().await.unwrap();
+        tenant_b.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn upserting_vectors_of_the_wrong_size_is_a_dimension_mismatch() {
+        let qdrant = test_wrapper();
+        let point = |id: u64, size: usize| test_point(&qdrant, id, json!({}), vec![0.1; size]);
+        let report = qdrant
+            .bulk_upsert_data(vec![point(1, 1536)], Some(1536), Some(TEST_VECTOR.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(report.succeeded, 1);
+        let error = qdrant
+            .bulk_upsert_data(vec![point(2, 512)], Some(512), Some(TEST_VECTOR.to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ProcessError>(),
+            Some(ProcessError::DimensionMismatch { expected: 1536, got: 512 })
+        ));
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn delete_by_filter_only_removes_matching_points() {
+        let qdrant = test_wrapper();
+        let points = (0..4)
+            .map(|i| {
+                let doc_id = if i < 3 { "handbook" } else { "faq" };
+                test_point(&qdrant, i, json!({"doc_id": doc_id}), vec![1.0, i as f32, 0.0, 0.0])
+            })
+            .collect();
+        upsert(&qdrant, points).await;
+        let filter = HashMap::from([(
+            "doc_id".to_string(),
+            HashMapValues::Str("handbook".to_string()),
+        )]);
+        assert_eq!(qdrant.delete_by_filter(filter).await.unwrap(), 3);
+        let (points, _) = qdrant.scroll(None, 10).await.unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].payload["doc_id"].as_str().unwrap(), "faq");
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn delete_by_filter_never_leaves_the_datasource() {
+        let collection_name = format!("test_{}", Uuid::new_v4().simple());
+        let datasource = |datasource_id: &str| {
+            let mut qdrant = Qdrant::new(test_client(), collection_name.clone());
+            qdrant.datasource_id = datasource_id.to_string();
+            qdrant
+        };
+        let (ds_a, ds_b) = (datasource("ds_a"), datasource("ds_b"));
+        upsert(&ds_a, vec![test_point(&ds_a, 1, json!({}), vec![1.0, 0.0, 0.0, 0.0])]).await;
+        upsert(&ds_b, vec![test_point(&ds_b, 2, json!({}), vec![0.0, 1.0, 0.0, 0.0])]).await;
+        // An empty filter matches the whole datasource, but only that datasource
+        assert_eq!(ds_a.delete_by_filter(HashMap::new()).await.unwrap(), 1);
+        assert_eq!(ds_b.count(true).await.unwrap(), 1);
+        ds_b.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn progress_increases_monotonically_up_to_the_total() {
+        let (progress_sender, mut progress_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let qdrant = test_wrapper()
+            .with_upsert_batch_size(3)
+            .with_progress(progress_sender);
+        let points = (0..10)
+            .map(|i| test_point(&qdrant, i, json!({}), vec![1.0, i as f32, 0.0, 0.0]))
+            .collect();
+        upsert(&qdrant, points).await;
+        let mut processed = vec![];
+        while let Ok(progress) = progress_receiver.try_recv() {
+            assert_eq!(progress.total, 10);
+            processed.push(progress.processed);
+        }
+        // One event per upserted chunk of 3 points
+        assert_eq!(processed, vec![3, 6, 9, 10]);
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    fn hybrid_point(qdrant: &Qdrant, id: u64, text: &str, dense: Vec<f32>) -> PointStruct {
+        let sparse = encode_document(text);
+        let vectors = HashMap::from([
+            (DENSE_VECTOR_NAME.to_string(), Vector::from(dense)),
+            (
+                SPARSE_VECTOR_NAME.to_string(),
+                Vector {
+                    data: sparse.values,
+                    indices: Some(SparseIndices {
+                        data: sparse.indices,
+                    }),
+                },
+            ),
+        ]);
+        let payload = json!({"datasource_id": qdrant.datasource_id, "page_content": text});
+        PointStruct::new(id, vectors, Payload::try_from(payload).unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn hybrid_search_fuses_the_dense_and_sparse_rankings() {
+        let qdrant = test_wrapper().with_hybrid_search(true);
+        let points = vec![
+            // Closest to the query vector but shares no terms with the query text
+            hybrid_point(&qdrant, 1, "garden furniture", vec![1.0, 0.0, 0.0, 0.0]),
+            // Matches both the vector and the terms
+            hybrid_point(&qdrant, 2, "oak desk lamp", vec![0.9, 0.1, 0.0, 0.0]),
+            // Only matches the terms
+            hybrid_point(&qdrant, 3, "brass desk lamp", vec![0.0, 0.0, 1.0, 0.0]),
+        ];
+        let report = qdrant
+            .bulk_upsert_data(points, Some(4), Some(DENSE_VECTOR_NAME.to_string()))
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty());
+        let query = EmbeddedQuery {
+            text: "desk lamp".to_string(),
+            vector: vec![1.0, 0.0, 0.0, 0.0],
+            vector_name: None,
+        };
+        let hits = qdrant.search_embedded(query, 3, None, None).await.unwrap();
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].payload["page_content"].as_str().unwrap(), "oak desk lamp");
+        // Each hit's score is its fused score, ranked best first
+        assert!(hits.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn hybrid_search_of_a_dense_only_collection_is_rejected() {
+        let qdrant = test_wrapper();
+        let point = test_point(&qdrant, 1, json!({}), vec![1.0, 0.0, 0.0, 0.0]);
+        upsert(&qdrant, vec![point]).await;
+        let hybrid = Qdrant::new(test_client(), qdrant.collection_name.clone())
+            .with_hybrid_search(true);
+        let error = hybrid
+            .search_embedded(test_query(vec![1.0, 0.0, 0.0, 0.0]), 1, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ProcessError>(),
+            Some(ProcessError::HybridSearchUnsupported(_))
+        ));
+        qdrant.delete_collection().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn min_score_follows_the_collection_distance() {
+        // Scored against the query [1, 0, 0, 0]: cosine similarities 1, 0.707 and 0; euclidean
+        // distances 0, 1 and 1.414
+        let vectors = [[1.0, 0.0, 0.0, 0.0], [1.0, 1.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0]];
+        for (distance, min_score) in [(Distance::Cosine, 0.5), (Distance::Euclid, 1.2)] {
+            let qdrant = test_wrapper().with_distance(distance);
+            let points = vectors
+                .iter()
+                .enumerate()
+                .map(|(i, vector)| test_point(&qdrant, i as u64, json!({}), vector.to_vec()))
+                .collect();
+            upsert(&qdrant, points).await;
+            let hits = qdrant
+                .search_embedded(test_query(vec![1.0, 0.0, 0.0, 0.0]), 10, None, Some(min_score))
+                .await
+                .unwrap();
+            // The two closest points pass, whichever way the metric counts
+            assert_eq!(hits.len(), 2, "{:?}", distance);
+            qdrant.delete_collection().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn documents_and_queries_are_cleaned_the_same_way() {
+        let preprocessor = TextPreprocessor::from("strip_html,collapse_whitespace,lowercase");
+        let html = concat!(
+            "<div>\n  <h1>Return   Policy</h1>\n",
+            "<p>Refunds take <b>five</b> days&nbsp;</p></div>"
+        );
+        let cleaned = "return policy refunds take five days";
+        // Only the cleaned text is cached, so the document can only be embedded once cleaned
+        let model = EmbeddingModels::OAI_SMALL;
+        embedding_cache()
+            .await
+            .insert(model.to_str().unwrap(), cleaned, vec![1.0, 0.0]);
+        let options = EmbeddingOptions {
+            preprocessor: preprocessor.clone(),
+            ..Default::default()
+        };
+        let record = HashMap::from([("text".to_string(), HashMapValues::Str(html.to_string()))]);
+        // The client only connects once it is used
+        let database = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap()
+            .database("test");
+        let points = embed_table_chunks_async(
+            Arc::new(RwLock::new(database)),
+            "ds".to_string(),
+            vec![record],
+            "text",
+            model,
+            &options,
+        )
+            .await
+            .unwrap();
+        assert_eq!(points.len(), 1);
+        // The query path applies the same steps before embedding the query
+        let qdrant = test_wrapper().with_text_preprocessor(preprocessor);
+        assert_eq!(qdrant.text_preprocessor.apply(html), cleaned);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running Qdrant instance"]
+    async fn scrolling_visits_every_point_of_the_datasource_once() {
+        let qdrant = test_wrapper();
+        let points = (0..23)
+            .map(|i| test_point(&qdrant, i, json!({}), vec![1.0, i as f32, 0.0, 0.0]))
+            .collect();
+        upsert(&qdrant, points).await;
+        let mut visited = vec![];
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let (points, next_cursor) = qdrant.scroll(cursor, 5).await.unwrap();
+            assert!(points.len() <= 5);
+            assert!(points.iter().all(|point| point.vector.is_none()));
+            visited.extend(points.into_iter().map(|point| point.id));
+            pages += 1;
+            cursor = next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(pages, 5);
+        visited.sort_by_key(|id| id.parse::<u64>().unwrap());
+        let expected: Vec<String> = (0..23).map(|i: u64| i.to_string()).collect();
+        assert_eq!(visited, expected);
+        qdrant.delete_collection().await.unwrap();
+    }
+}
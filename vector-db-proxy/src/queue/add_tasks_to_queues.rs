@@ -1,4 +1,5 @@
-use crate::queue::queuing::{Control, MyQueue};
+use crate::data::models::MessageFormat;
+use crate::queue::queuing::{Control, MyQueue, ProcessingResultSender};
 use mongodb::Database;
 use qdrant_client::client::QdrantClient;
 use std::sync::Arc;
@@ -7,9 +8,12 @@ use tokio::sync::RwLock;
 /// Adds the incoming task to the execution Queue to be processes when threads are available
 pub async fn add_message_to_embedding_queue(
     queue: Arc<RwLock<MyQueue<String>>>,
-    qdrant_conn: Arc<RwLock<QdrantClient>>,
+    qdrant_conn: Arc<QdrantClient>,
     mongo_conn: Arc<RwLock<Database>>,
     params: (String, String),
+    message_format: MessageFormat,
+    message_id: Option<String>,
+    on_complete: Option<ProcessingResultSender>,
 ) {
     let (dataset_id, table_name) = params;
     // Instantiate a new instance of the MyQueue
@@ -17,5 +21,12 @@ pub async fn add_message_to_embedding_queue(
     // Add task to queue
     q_guard.enqueue(dataset_id);
     // Call associated function to being processing tasks in the queue
-    q_guard.embed_message(qdrant_conn, mongo_conn, table_name);
+    q_guard.embed_message(
+        qdrant_conn,
+        mongo_conn,
+        table_name,
+        message_format,
+        message_id,
+        on_complete,
+    );
 }
\ No newline at end of file
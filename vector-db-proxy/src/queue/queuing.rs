@@ -4,7 +4,7 @@ use std::fmt::Debug;
 use std::marker::Send;
 use std::sync::Arc;
 use std::thread::available_parallelism;
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, RwLock};
 
 use queues::Queue;
 use queues::*;
@@ -12,7 +12,18 @@ use threadpool::ThreadPool;
 
 use qdrant_client::client::QdrantClient;
 
+use crate::data::errors::ProcessError;
+use crate::data::models::MessageFormat;
 use crate::data::processing_incoming_messages::process_messages;
+use crate::idempotency::mongo_store::MongoIdempotencyStore;
+use crate::idempotency::traits::IdempotencyStore;
+use crate::qdrant::helpers::lookup_datasource_tenant;
+use crate::qdrant::models::UpsertReport;
+use crate::qdrant::utils::Qdrant;
+use crate::vector_store::traits::VectorStore;
+
+/// Receives the outcome of processing a message, e.g. to ack or nack the delivery it came from
+pub type ProcessingResultSender = oneshot::Sender<Result<UpsertReport, ProcessError>>;
 
 // This is essentially the Class
 // The requirement for T to be Clone is a constraint of the queues crate
@@ -32,9 +43,12 @@ pub trait Control<T>
     fn enqueue(&mut self, task: T);
     fn embed_message(
         &mut self,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
+        qdrant_conn: Arc<QdrantClient>,
         mongo_conn: Arc<RwLock<Database>>,
         message: String,
+        message_format: MessageFormat,
+        message_id: Option<String>,
+        on_complete: Option<ProcessingResultSender>,
     ) -> bool;
 }
 
@@ -99,9 +113,12 @@ impl<T: Clone + Send> Control<T> for MyQueue<T>
 
     fn embed_message(
         &mut self,
-        qdrant_conn: Arc<RwLock<QdrantClient>>,
+        qdrant_conn: Arc<QdrantClient>,
         mongo_conn: Arc<RwLock<Database>>,
         message: String,
+        message_format: MessageFormat,
+        message_id: Option<String>,
+        mut on_complete: Option<ProcessingResultSender>,
     ) -> bool {
         while self.q.size() > 0 {
             let task = match self.q.remove() {
@@ -116,10 +133,55 @@ impl<T: Clone + Send> Control<T> for MyQueue<T>
             let data = message.clone();
             let qdrant_client = Arc::clone(&qdrant_conn);
             let mongo_client = Arc::clone(&mongo_conn);
+            let data_message_id = message_id.clone();
+            let result_sender = on_complete.take();
             self.pool.execute(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async {
-                    process_messages(qdrant_client, mongo_client, data, id).await;
+                    let datasource_id = id.clone();
+                    let result = match lookup_datasource_tenant(&mongo_client, &id).await {
+                        Ok(tenant_id) => {
+                            let vector_store: Arc<dyn VectorStore> = Arc::new(
+                                Qdrant::from_global_data(qdrant_client, id.clone(), tenant_id)
+                                    .await,
+                            );
+                            let idempotency_store: Arc<dyn IdempotencyStore> =
+                                Arc::new(MongoIdempotencyStore::new(Arc::clone(&mongo_client)));
+                            process_messages(
+                                vector_store,
+                                mongo_client,
+                                data,
+                                message_format,
+                                id,
+                                data_message_id,
+                                idempotency_store,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(ProcessError::TenantLookupFailed(e)),
+                    };
+                    match &result {
+                        Ok(report) => {
+                            println!(
+                                "{} points upserted for datasource: {}",
+                                report.succeeded, datasource_id
+                            );
+                            for failed_point in &report.failed {
+                                eprintln!(
+                                    "Point {} for datasource: {} failed to upsert. Error: {}",
+                                    failed_point.id, datasource_id, failed_point.reason
+                                );
+                            }
+                        }
+                        Err(e) => eprintln!(
+                            "An error occurred while processing message for datasource: {}. Error: {}",
+                            datasource_id, e
+                        ),
+                    }
+                    if let Some(sender) = result_sender {
+                        // The receiver is gone if whoever enqueued the message stopped waiting
+                        let _ = sender.send(result);
+                    }
                 })
             });
         }
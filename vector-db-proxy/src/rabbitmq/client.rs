@@ -7,6 +7,7 @@ use amqprs::{
     connection::{Connection, OpenConnectionArguments},
 };
 use tokio::time::{sleep, Duration};
+use tracing::warn;
 
 pub async fn connect_rabbitmq(connection_details: &RabbitConnect) -> Connection {
     let mut res = Connection::open(
@@ -56,6 +57,7 @@ pub async fn bind_queue_to_exchange(
     exchange: &str,
     queue: &str,
     routing_key: &str,
+    prefetch_count: u16,
 ) {
     if !connection.is_open() {
         println!("Connection not open");
@@ -68,10 +70,11 @@ pub async fn bind_queue_to_exchange(
         .exchange_declare(ExchangeDeclareArguments::new(exchange, "direct"))
         .await
         .unwrap();
-    // Setting up basic quality-of-service parameters for the channel to enable streaming queue
+    // Setting up basic quality-of-service parameters for the channel to enable streaming queue.
+    // Messages are only acked once processed so this also bounds the number of messages in flight
     match channel
         .basic_qos(BasicQosArguments {
-            prefetch_count: 10000,
+            prefetch_count,
             prefetch_size: 0,
             global: false,
         })
@@ -120,3 +123,45 @@ pub async fn bind_queue_to_exchange(
         }
     }
 }
+
+/// Declares the (classic, durable) queue that permanently failing messages are moved to
+pub async fn declare_dead_letter_queue(channel: &Channel, queue: &str) {
+    if let Err(e) = channel
+        .queue_declare(
+            QueueDeclareArguments::default()
+                .queue(queue.to_owned())
+                .durable(true)
+                .finish(),
+        )
+        .await
+    {
+        warn!(queue, error = %e, "Could not declare the dead letter queue")
+    }
+}
+
+/// The queue failed messages wait in before they are retried
+pub fn retry_queue_name(queue: &str) -> String {
+    format!("{}.retry", queue)
+}
+
+/// Declares the (classic, durable) queue that failed messages wait in before being retried. Each
+/// retry is published with a per-message TTL, once it expires the broker dead letters the message
+/// back onto `queue` through the default exchange, so other queues bound to the exchange don't
+/// see it again
+pub async fn declare_retry_queue(channel: &Channel, queue: &str) {
+    let mut args: FieldTable = FieldTable::new();
+    args.insert("x-dead-letter-exchange".try_into().unwrap(), "".into());
+    args.insert("x-dead-letter-routing-key".try_into().unwrap(), queue.into());
+    if let Err(e) = channel
+        .queue_declare(
+            QueueDeclareArguments::default()
+                .queue(retry_queue_name(queue))
+                .durable(true)
+                .arguments(args)
+                .finish(),
+        )
+        .await
+    {
+        warn!(queue, error = %e, "Could not declare the retry queue")
+    }
+}
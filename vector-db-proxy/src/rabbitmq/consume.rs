@@ -1,144 +1,474 @@
 use std::sync::Arc;
 
-use amqp_serde::types::ShortStr;
-use amqprs::channel::{BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, Channel};
+use amqp_serde::types::{FieldTable, ShortStr};
+use amqprs::channel::{
+    BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments,
+    BasicPublishArguments, Channel, ConsumerMessage,
+};
 use mongodb::Database;
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::PointStruct;
 use serde_json::Value;
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, RwLock};
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
 
+use crate::data::errors::ProcessError;
+use crate::data::models::MessageFormat;
 use crate::data::utils::{apply_chunking_strategy_to_document, extract_text_from_file};
+use crate::init::env_variables::GLOBAL_DATA;
 use crate::llm::models::EmbeddingModels;
 use crate::mongo::{models::ChunkingStrategy, queries::get_embedding_model};
 use crate::mongo::queries::get_datasource;
-use crate::qdrant::{helpers::construct_point_struct, utils::Qdrant};
+use crate::qdrant::{
+    helpers::{construct_point_struct, datasource_tenant},
+    models::UpsertReport,
+    utils::Qdrant,
+};
 use crate::queue::add_tasks_to_queues::add_message_to_embedding_queue;
 use crate::queue::queuing::MyQueue;
+use crate::rabbitmq::client::{
+    bind_queue_to_exchange, channel_rabbitmq, connect_rabbitmq, declare_dead_letter_queue,
+    declare_retry_queue, retry_queue_name,
+};
+use crate::rabbitmq::models::{DeadLetterPolicy, Delivery, QueueBinding, RabbitConnect};
+use crate::rabbitmq::shutdown::{InFlightGuard, Shutdown};
 use crate::utils::file_operations;
 use crate::utils::file_operations::save_file_to_disk;
 use crate::utils::webhook::send_webapp_embed_ready;
 
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// Longest a failed message waits before it is retried
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
 pub async fn subscribe_to_queue(
     // redis_connection_pool: Arc<Mutex<RedisConnection>>,
-    qdrant_clone: Arc<RwLock<QdrantClient>>,
+    qdrant_clone: Arc<QdrantClient>,
     queue: Arc<RwLock<MyQueue<String>>>,
     mongo_client: Arc<RwLock<Database>>,
     channel: &Channel,
     queue_name: &String,
+    dead_letter_policy: &DeadLetterPolicy,
+    shutdown: &Arc<Shutdown>,
 ) {
     let mongodb_connection = mongo_client.read().await;
     let args = BasicConsumeArguments::new(queue_name, "");
     match channel.basic_consume_rx(args.clone()).await {
         Ok((ctag, mut messages_rx)) => {
-            loop {
-                while let Some(message) = messages_rx.recv().await {
-                    let args = BasicAckArguments::new(message.deliver.unwrap().delivery_tag(), false);
-                    let _ = channel.basic_ack(args).await;
-                    let headers = message.basic_properties.unwrap().headers().unwrap().clone();
-                    if let Some(stream) = headers.get(&ShortStr::try_from("stream").unwrap()) {
-                        let stream_string: String = stream.to_string();
-                        let stream_split: Vec<&str> = stream_string.split('_').collect();
-                        let datasource_id = stream_split.to_vec()[0];
-                        if let Some(msg) = message.content {
-                            // if the header 'type' is present then assume that it is a file upload. pull from gcs
-                            if let Ok(message_string) = String::from_utf8(msg.clone().to_vec()) {
-                                match get_datasource(&mongodb_connection, datasource_id).await {
-                                    Ok(datasource) => {
-                                        if let Some(ds) = datasource {
-                                            if let Ok(Some(model_parameters)) = get_embedding_model(&mongodb_connection, datasource_id).await {
-                                                if headers.get(&ShortStr::try_from("type").unwrap()).is_some() {
-                                                    if let Ok(_json) = serde_json::from_str(message_string.as_str()) {
-                                                        let message_data: Value = _json; // this is necessary because  you can not do type annotation inside a if let Ok() expression
-                                                        match file_operations::read_file_from_source(headers, message_data).await {
-                                                            Some((file_type, file, file_path)) => {
-                                                                save_file_to_disk(file, file_path.as_str()).await.unwrap();
-                                                                let message_queue = Arc::clone(&queue);
-                                                                let qdrant_conn = Arc::clone(&qdrant_clone);
-                                                                let mongo_conn = Arc::clone(&mongo_client);
-                                                                // let redis_conn = Arc::clone(&redis_connection_pool);
-                                                                let datasource_clone = ds.clone();
-                                                                let (document_text, metadata) =
-                                                                    extract_text_from_file(file_type, file_path.as_str(), ds.originalName, datasource_id.to_string(), message_queue, qdrant_conn, mongo_conn).await.unwrap();
-                                                                // dynamically get user's chunking strategy of choice from the database
-                                                                let model_obj_clone = model_parameters.clone();
-                                                                let model_name = model_obj_clone.model;
-                                                                let chunking_character = datasource_clone.chunkCharacter;
-                                                                let chunking_method = datasource_clone.chunkStrategy.unwrap();
-                                                                let chunking_strategy = ChunkingStrategy::from(chunking_method);
-                                                                let mongo_conn_clone = Arc::clone(&mongo_client);
-                                                                match apply_chunking_strategy_to_document(document_text, metadata, chunking_strategy, chunking_character, Some(model_parameters.model), mongo_conn_clone, datasource_id.to_string())
-                                                                    .await {
-                                                                    Ok(chunks) => {
-                                                                        let mut points_to_upload: Vec<PointStruct> = vec![];
-                                                                        for element in chunks.iter() {
-                                                                            let embedding_vector =
-                                                                                &element.embedding_vector;
-                                                                            match embedding_vector {
-                                                                                Some(val) => {
-                                                                                    let model = EmbeddingModels::from(model_name.clone());
-                                                                                    if let Some(point_struct) = construct_point_struct(val, element.metadata.clone().unwrap(), Some(model)).await {
-                                                                                        points_to_upload.push(point_struct)
-                                                                                    }
-                                                                                }
-                                                                                None => {
-                                                                                    println!("Embedding vector was empty!")
+            // The receiver closes once the channel or connection goes away
+            while let Some(message) = next_message(&mut messages_rx, shutdown).await {
+                let Some(deliver) = message.deliver else {
+                    continue;
+                };
+                let delivery_tag = deliver.delivery_tag();
+                // Messages handed to process_messages are acked (or retried) once processed
+                let mut ack_deferred = false;
+                let properties = message.basic_properties.unwrap_or_default();
+                let headers = properties.headers().cloned().unwrap_or_default();
+                // The datasource is taken from the stream header, falling back to the routing key
+                let stream_string: String = match headers.get(&ShortStr::try_from("stream").unwrap()) {
+                    Some(stream) => stream.to_string(),
+                    None => deliver.routing_key().to_owned(),
+                };
+                if !stream_string.is_empty() {
+                    let datasource_id = stream_string.split('_').next().unwrap_or_default();
+                    if let Some(msg) = message.content {
+                        // if the header 'type' is present then assume that it is a file upload. pull from gcs
+                        if let Ok(message_string) = String::from_utf8(msg.clone().to_vec()) {
+                            match get_datasource(&mongodb_connection, datasource_id).await {
+                                Ok(datasource) => {
+                                    if let Some(ds) = datasource {
+                                        if let Ok(Some(model_parameters)) = get_embedding_model(&mongodb_connection, datasource_id).await {
+                                            if headers.get(&ShortStr::try_from("type").unwrap()).is_some() {
+                                                if let Ok(_json) = serde_json::from_str(message_string.as_str()) {
+                                                    let message_data: Value = _json; // this is necessary because  you can not do type annotation inside a if let Ok() expression
+                                                    match file_operations::read_file_from_source(headers, message_data).await {
+                                                        Some((file_type, file, file_path)) => {
+                                                            save_file_to_disk(file, file_path.as_str()).await.unwrap();
+                                                            let message_queue = Arc::clone(&queue);
+                                                            let qdrant_conn = Arc::clone(&qdrant_clone);
+                                                            let mongo_conn = Arc::clone(&mongo_client);
+                                                            // let redis_conn = Arc::clone(&redis_connection_pool);
+                                                            let datasource_clone = ds.clone();
+                                                            let tenant_id = datasource_tenant(&ds).await;
+                                                            let (document_text, metadata) =
+                                                                extract_text_from_file(file_type, file_path.as_str(), ds.originalName, datasource_id.to_string(), message_queue, qdrant_conn, mongo_conn).await.unwrap();
+                                                            // dynamically get user's chunking strategy of choice from the database
+                                                            let model_obj_clone = model_parameters.clone();
+                                                            let model_name = model_obj_clone.model;
+                                                            let chunking_character = datasource_clone.chunkCharacter;
+                                                            let chunking_method = datasource_clone.chunkStrategy.unwrap();
+                                                            let chunking_strategy = ChunkingStrategy::from(chunking_method);
+                                                            let mongo_conn_clone = Arc::clone(&mongo_client);
+                                                            match apply_chunking_strategy_to_document(document_text, metadata, chunking_strategy, chunking_character, Some(model_parameters.model), mongo_conn_clone, datasource_id.to_string())
+                                                                .await {
+                                                                Ok(chunks) => {
+                                                                    let mut points_to_upload: Vec<PointStruct> = vec![];
+                                                                    for element in chunks.iter() {
+                                                                        let embedding_vector =
+                                                                            &element.embedding_vector;
+                                                                        match embedding_vector {
+                                                                            Some(val) => {
+                                                                                let model = EmbeddingModels::from(model_name.clone());
+                                                                                let mut metadata = element.metadata.clone().unwrap();
+                                                                                metadata.insert("datasource_id".to_string(), datasource_id.to_string());
+                                                                                if let Some(point_struct) = construct_point_struct(val, metadata, Some(model)).await {
+                                                                                    points_to_upload.push(point_struct)
                                                                                 }
                                                                             }
-                                                                        }
-                                                                        let vector_length = model_parameters.embeddingLength as u64;
-                                                                        let qdrant_conn_clone = Arc::clone(&qdrant_clone);
-                                                                        let qdrant = Qdrant::new(qdrant_conn_clone, datasource_id.to_string());
-                                                                        match qdrant.bulk_upsert_data(points_to_upload, Some(vector_length), Some(model_name)).await {
-                                                                            Ok(_) => {
-                                                                                println!("points uploaded successfully!");
-                                                                                if let Err(e) = send_webapp_embed_ready(&datasource_id).await {
-                                                                                    println!("Error notifying webapp: {}", e);
-                                                                                } else {
-                                                                                    println!("Webapp notified successfully!");
-                                                                                }
+                                                                            None => {
+                                                                                println!("Embedding vector was empty!")
                                                                             }
-                                                                            Err(e) => {
-                                                                                println!("An error occurred while attempting upload to qdrant. Error: {:?}", e);
+                                                                        }
+                                                                    }
+                                                                    let vector_length = model_parameters.embeddingLength as u64;
+                                                                    let qdrant_conn_clone = Arc::clone(&qdrant_clone);
+                                                                    let qdrant = Qdrant::from_global_data(qdrant_conn_clone, datasource_id.to_string(), tenant_id).await;
+                                                                    let total = points_to_upload.len();
+                                                                    match qdrant.bulk_upsert_data(points_to_upload, Some(vector_length), Some(model_name)).await {
+                                                                        Ok(report) => {
+                                                                            println!("{} out of {} points uploaded successfully!", report.succeeded, total);
+                                                                            if let Err(e) = send_webapp_embed_ready(&datasource_id).await {
+                                                                                println!("Error notifying webapp: {}", e);
+                                                                            } else {
+                                                                                println!("Webapp notified successfully!");
                                                                             }
                                                                         }
+                                                                        Err(e) => {
+                                                                            println!("An error occurred while attempting upload to qdrant. Error: {:?}", e);
+                                                                        }
                                                                     }
-                                                                    Err(e) => println!("Error: {}", e),
                                                                 }
+                                                                Err(e) => println!("Error: {}", e),
                                                             }
-                                                            None => {
-                                                                println!("Could not read file from source...source returned NONE!")
-                                                            }
+                                                        }
+                                                        None => {
+                                                            println!("Could not read file from source...source returned NONE!")
                                                         }
                                                     }
-                                                } else {
-                                                    // This is where data is coming from airbyte rather than a direct file upload
-                                                    let message_queue = Arc::clone(&queue);
-                                                    let qdrant_conn = Arc::clone(&qdrant_clone);
-                                                    let mongo_conn = Arc::clone(&mongo_client);
-                                                    let _ = add_message_to_embedding_queue(message_queue, qdrant_conn, mongo_conn, (datasource_id.to_string(), message_string)).await;
                                                 }
+                                            } else {
+                                                // This is where data is coming from airbyte rather than a direct file upload
+                                                let message_queue = Arc::clone(&queue);
+                                                let qdrant_conn = Arc::clone(&qdrant_clone);
+                                                let mongo_conn = Arc::clone(&mongo_client);
+                                                let (result_sender, result_receiver) = oneshot::channel();
+                                                // Retries republish the original properties, so redeliveries keep the message id
+                                                let message_id = properties.message_id().map(|id| id.to_string());
+                                                // Held until the message is acked so that shutdown waits for it
+                                                let in_flight = shutdown.track(format!(
+                                                    "datasource: {}, delivery: {}, message id: {}",
+                                                    datasource_id,
+                                                    delivery_tag,
+                                                    message_id.as_deref().unwrap_or("none")
+                                                ));
+                                                let message_format = MessageFormat::from(properties.content_type().map(|c| c.as_str()));
+                                                let _ = add_message_to_embedding_queue(message_queue, qdrant_conn, mongo_conn, (datasource_id.to_string(), message_string), message_format, message_id, Some(result_sender)).await;
+                                                ack_deferred = true;
+                                                let delivery = Delivery {
+                                                    delivery_tag,
+                                                    queue: queue_name.clone(),
+                                                    properties: properties.clone(),
+                                                    content: msg.clone(),
+                                                };
+                                                tokio::spawn(acknowledge_when_processed(channel.clone(), delivery, dead_letter_policy.clone(), result_receiver, in_flight));
                                             }
-                                        } else {
-                                            eprintln!(
-                                                "There was no embedding model associated with datasource: {}",
-                                                datasource_id
-                                            )
                                         }
+                                    } else {
+                                        eprintln!(
+                                            "There was no embedding model associated with datasource: {}",
+                                            datasource_id
+                                        )
                                     }
-                                    Err(e) => { println!("Could not find associated datasource: {}", e) }
                                 }
+                                Err(e) => { println!("Could not find associated datasource: {}", e) }
                             }
-                        } else {
-                            println!("There was no stream_id in message... can not upload data!");
                         }
+                    } else {
+                        println!("There was no stream_id in message... can not upload data!");
                     }
-                    if let Err(e) = channel.basic_cancel(BasicCancelArguments::new(&ctag)).await {
-                        println!("error {}", e);
-                    };
+                }
+                if !ack_deferred {
+                    let _ = channel.basic_ack(BasicAckArguments::new(delivery_tag, false)).await;
                 }
             }
+            if let Err(e) = channel.basic_cancel(BasicCancelArguments::new(&ctag)).await {
+                println!("error {}", e);
+            };
         }
         Err(e) => { println!("Error consuming message from rabbit: {}", e) }
     }
 }
+
+/// The next delivery, or None once the channel closes or shutdown is triggered
+async fn next_message(
+    messages_rx: &mut UnboundedReceiver<ConsumerMessage>,
+    shutdown: &Shutdown,
+) -> Option<ConsumerMessage> {
+    tokio::select! {
+        message = messages_rx.recv() => message,
+        _ = shutdown.triggered() => None,
+    }
+}
+
+/// Acks the delivery once its message has been processed successfully. If processing failed the
+/// message is republished to the retry queue with its attempt count in the `x-retry-count` header,
+/// from where it goes back onto the consumed queue after a backoff delay. Once it has failed
+/// `max_redeliveries` times it goes to the dead letter queue instead, with the error in the
+/// `x-failure-reason` header. Either way the original delivery is acked off the main queue.
+/// `_in_flight` is released once the delivery has been dealt with
+async fn acknowledge_when_processed(
+    channel: Channel,
+    delivery: Delivery,
+    dead_letter_policy: DeadLetterPolicy,
+    result_receiver: oneshot::Receiver<Result<UpsertReport, ProcessError>>,
+    _in_flight: InFlightGuard,
+) {
+    let failure_reason = match result_receiver.await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("message processing was aborted".to_string()),
+    };
+    if let Some(reason) = failure_reason {
+        if let Err(e) = republish_failed_message(&channel, &delivery, &dead_letter_policy, reason).await {
+            // Leave it to the broker to redeliver rather than lose the message
+            warn!(
+                delivery_tag = delivery.delivery_tag,
+                error = %e,
+                "Could not republish failed message, leaving it to the broker to redeliver"
+            );
+            if let Err(e) = channel
+                .basic_nack(BasicNackArguments::new(delivery.delivery_tag, false, true))
+                .await
+            {
+                warn!(delivery_tag = delivery.delivery_tag, error = %e, "Could not nack message");
+            }
+            return;
+        }
+    }
+    if let Err(e) = channel
+        .basic_ack(BasicAckArguments::new(delivery.delivery_tag, false))
+        .await
+    {
+        warn!(delivery_tag = delivery.delivery_tag, error = %e, "Could not ack message");
+    }
+}
+
+/// Where a failed message goes next
+#[derive(Debug, PartialEq)]
+enum FailedMessageRoute {
+    /// Back onto the consumed queue after `delay`
+    Retry { attempts: u32, delay: Duration },
+    /// Onto the dead letter queue, it won't be retried again
+    DeadLetter { attempts: u32 },
+}
+
+/// Counts the failed attempt in the message's `x-retry-count` header and decides whether it is
+/// retried. On its `max_redeliveries`th failure the message is dead lettered instead, with the
+/// error in the `x-failure-reason` header
+fn route_failed_message(
+    headers: &mut FieldTable,
+    dead_letter_policy: &DeadLetterPolicy,
+    reason: String,
+) -> FailedMessageRoute {
+    let retry_count_key: ShortStr = "x-retry-count".try_into().unwrap();
+    let attempts = headers
+        .remove(&retry_count_key)
+        .and_then(|retry_count| retry_count.to_string().parse::<u32>().ok())
+        .unwrap_or(0)
+        + 1;
+    headers.insert(retry_count_key, attempts.to_string().into());
+    if attempts >= dead_letter_policy.max_redeliveries {
+        headers.insert("x-failure-reason".try_into().unwrap(), reason.into());
+        return FailedMessageRoute::DeadLetter { attempts };
+    }
+    FailedMessageRoute::Retry {
+        attempts,
+        delay: retry_delay(dead_letter_policy.retry_base_delay, attempts),
+    }
+}
+
+async fn republish_failed_message(
+    channel: &Channel,
+    delivery: &Delivery,
+    dead_letter_policy: &DeadLetterPolicy,
+    reason: String,
+) -> Result<(), amqprs::error::Error> {
+    let mut headers = delivery.properties.headers().cloned().unwrap_or_default();
+    let mut properties = delivery.properties.clone();
+    let route = route_failed_message(&mut headers, dead_letter_policy, reason.clone());
+    let publish_arguments = match route {
+        FailedMessageRoute::DeadLetter { attempts } => {
+            warn!(
+                delivery_tag = delivery.delivery_tag,
+                attempts,
+                dead_letter_queue = %dead_letter_policy.queue,
+                reason = %reason,
+                "Message failed too many times, moving it to the dead letter queue"
+            );
+            BasicPublishArguments::new("", &dead_letter_policy.queue)
+        }
+        FailedMessageRoute::Retry { attempts, delay } => {
+            warn!(
+                delivery_tag = delivery.delivery_tag,
+                attempts,
+                delay_ms = delay.as_millis() as u64,
+                reason = %reason,
+                "Message failed, retrying after a delay"
+            );
+            // Expires into the consumed queue, see `declare_retry_queue`
+            properties.with_expiration(&delay.as_millis().to_string());
+            BasicPublishArguments::new("", &retry_queue_name(&delivery.queue))
+        }
+    };
+    properties.with_headers(headers);
+    channel
+        .basic_publish(properties, delivery.content.clone(), publish_arguments)
+        .await
+}
+
+/// How long a message waits before its next attempt, doubling with every failed attempt. The
+/// retry queue only expires the message at its head, so a message can wait a little longer when a
+/// message with a longer delay is ahead of it
+fn retry_delay(base_delay: Duration, attempts: u32) -> Duration {
+    base_delay
+        .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+///
+///
+/// # Arguments
+///
+/// * `connection_details`: Where to find the broker
+/// * `binding`: The queue to consume and how it is bound
+/// * `shutdown`: Once triggered consuming stops and the messages already handed off for
+/// processing are drained before the connection is closed
+///
+/// Connects to RabbitMQ and consumes `queue_name`, reconnecting whenever the broker drops the
+/// connection. Only returns once `shutdown` has been triggered and drained
+///
+/// returns: ()
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn consume_with_reconnect(
+    qdrant_conn: Arc<QdrantClient>,
+    queue: Arc<RwLock<MyQueue<String>>>,
+    mongo_client: Arc<RwLock<Database>>,
+    connection_details: RabbitConnect,
+    binding: QueueBinding,
+    shutdown: Arc<Shutdown>,
+) {
+    let global_data = GLOBAL_DATA.read().await;
+    let prefetch_count = global_data.rabbitmq_prefetch_count;
+    let dead_letter_policy = DeadLetterPolicy {
+        queue: global_data.rabbitmq_dead_letter_queue.clone(),
+        max_redeliveries: global_data.rabbitmq_max_redeliveries,
+        retry_base_delay: Duration::from_millis(global_data.rabbitmq_retry_base_delay_ms),
+    };
+    drop(global_data);
+    loop {
+        let mut connection = connect_rabbitmq(&connection_details).await;
+        let mut channel = channel_rabbitmq(&connection).await;
+        declare_dead_letter_queue(&channel, &dead_letter_policy.queue).await;
+        declare_retry_queue(&channel, &binding.queue).await;
+        bind_queue_to_exchange(
+            &mut connection,
+            &mut channel,
+            &connection_details,
+            &binding.exchange,
+            &binding.queue,
+            &binding.routing_key,
+            prefetch_count,
+        )
+            .await;
+        subscribe_to_queue(
+            Arc::clone(&qdrant_conn),
+            Arc::clone(&queue),
+            Arc::clone(&mongo_client),
+            &channel,
+            &binding.queue,
+            &dead_letter_policy,
+            &shutdown,
+        )
+            .await;
+        if shutdown.is_triggered() {
+            // Acks for the drained messages go out over this channel, so it has to stay open
+            shutdown.drain().await;
+            if let Err(e) = connection.close().await {
+                warn!(error = %e, "Could not close the RabbitMQ connection");
+            }
+            return;
+        }
+        info!("Lost connection to RabbitMQ, reconnecting");
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_with_each_attempt_up_to_the_cap() {
+        let base_delay = Duration::from_secs(1);
+        assert_eq!(retry_delay(base_delay, 1), Duration::from_secs(1));
+        assert_eq!(retry_delay(base_delay, 2), Duration::from_secs(2));
+        assert_eq!(retry_delay(base_delay, 4), Duration::from_secs(8));
+        assert_eq!(retry_delay(base_delay, 40), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn an_always_failing_message_is_dead_lettered_after_max_redeliveries() {
+        let dead_letter_policy = DeadLetterPolicy {
+            queue: "dead_letter".to_string(),
+            max_redeliveries: 3,
+            retry_base_delay: Duration::from_secs(1),
+        };
+        let mut headers = FieldTable::new();
+        let mut attempts = 0;
+        // Each retry is redelivered with the headers it was republished with
+        let route = loop {
+            attempts += 1;
+            let route = route_failed_message(
+                &mut headers,
+                &dead_letter_policy,
+                "Embedding request timed out".to_string(),
+            );
+            if !matches!(route, FailedMessageRoute::Retry { .. }) {
+                break route;
+            }
+        };
+        assert_eq!(attempts, 3);
+        assert_eq!(route, FailedMessageRoute::DeadLetter { attempts: 3 });
+        let failure_reason = headers.get(&"x-failure-reason".try_into().unwrap());
+        assert_eq!(
+            failure_reason.map(|reason| reason.to_string()),
+            Some("Embedding request timed out".to_string())
+        );
+    }
+
+    #[test]
+    fn retries_are_counted_in_the_retry_count_header() {
+        let dead_letter_policy = DeadLetterPolicy {
+            queue: "dead_letter".to_string(),
+            max_redeliveries: 3,
+            retry_base_delay: Duration::from_secs(1),
+        };
+        let mut headers = FieldTable::new();
+        headers.insert("x-retry-count".try_into().unwrap(), "1".into());
+        let route = route_failed_message(&mut headers, &dead_letter_policy, "failed".to_string());
+        assert_eq!(
+            route,
+            FailedMessageRoute::Retry { attempts: 2, delay: Duration::from_secs(2) }
+        );
+        let retry_count = headers.get(&"x-retry-count".try_into().unwrap());
+        assert_eq!(retry_count.map(|count| count.to_string()), Some("2".to_string()));
+        assert!(headers.get(&"x-failure-reason".try_into().unwrap()).is_none());
+    }
+}
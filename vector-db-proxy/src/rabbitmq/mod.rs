@@ -1,3 +1,4 @@
 pub mod client;
 pub mod consume;
-pub mod models;
\ No newline at end of file
+pub mod models;
+pub mod shutdown;
\ No newline at end of file
@@ -1,6 +1,35 @@
+use amqprs::BasicProperties;
+use std::time::Duration;
+
 pub struct RabbitConnect {
     pub host: String,
     pub port: u16,
     pub username: String,
     pub password: String,
-}
\ No newline at end of file
+}
+
+/// The queue to consume and how it is bound to the exchange
+pub struct QueueBinding {
+    pub exchange: String,
+    pub queue: String,
+    pub routing_key: String,
+}
+
+/// A delivery that was handed off for processing, kept so that it can be republished if
+/// processing fails
+pub struct Delivery {
+    pub delivery_tag: u64,
+    /// The queue the message was consumed from, retries are published back to it
+    pub queue: String,
+    pub properties: BasicProperties,
+    pub content: Vec<u8>,
+}
+
+/// Messages that fail `max_redeliveries` times are moved to `queue` rather than retried forever.
+/// Retries wait `retry_base_delay`, doubling with each attempt, before they are redelivered
+#[derive(Clone, Debug)]
+pub struct DeadLetterPolicy {
+    pub queue: String,
+    pub max_redeliveries: u32,
+    pub retry_base_delay: Duration,
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+// How often `drain` checks whether the in-flight work has finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Coordinates shutting the consumer down without losing messages. Once triggered the consumer
+/// stops taking deliveries, then `drain` waits for the messages already handed off for
+/// processing (tracked with `track`) to be upserted and acked, for up to `grace_period`
+pub struct Shutdown {
+    triggered: watch::Sender<bool>,
+    in_flight: Mutex<HashMap<u64, String>>,
+    next_id: AtomicU64,
+    grace_period: Duration,
+}
+
+/// Marks a message as in flight until it is dropped
+pub struct InFlightGuard {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.shutdown.in_flight().remove(&self.id);
+    }
+}
+
+impl Shutdown {
+    pub fn new(grace_period: Duration) -> Self {
+        let (triggered, _) = watch::channel(false);
+        Shutdown {
+            triggered,
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            grace_period,
+        }
+    }
+
+    fn in_flight(&self) -> MutexGuard<'_, HashMap<u64, String>> {
+        match self.in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Resolves once the shutdown has been triggered
+    pub async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Tracks a piece of work until the returned guard is dropped. `description` is what gets
+    /// logged if the work is still running when the grace period expires
+    pub fn track(self: &Arc<Self>, description: String) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.in_flight().insert(id, description);
+        InFlightGuard {
+            shutdown: Arc::clone(self),
+            id,
+        }
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight().len()
+    }
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// Waits for all tracked work to finish, giving up after the grace period. Work that is
+    /// still in flight by then is logged so that it can be checked (its messages weren't acked
+    /// so the broker redelivers them)
+    ///
+    /// returns: bool whether everything finished in time
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///
+    /// ```
+    pub async fn drain(&self) -> bool {
+        let deadline = Instant::now() + self.grace_period;
+        info!(
+            in_flight = self.in_flight_count(),
+            grace_period = ?self.grace_period,
+            "Draining in-flight messages"
+        );
+        while self.in_flight_count() > 0 {
+            if Instant::now() >= deadline {
+                let in_flight = self.in_flight();
+                warn!(
+                    remaining = in_flight.len(),
+                    "Grace period expired before all in-flight messages were processed"
+                );
+                for description in in_flight.values() {
+                    warn!("Did not complete: {}", description);
+                }
+                return false;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        info!("All in-flight messages were processed");
+        true
+    }
+}
+
+/// Resolves on SIGTERM (sent by orchestrators when replacing a deployment) or SIGINT
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let (Ok(mut terminate), Ok(mut interrupt)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) else {
+            warn!("Could not listen for shutdown signals");
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = interrupt.recv() => {},
+        }
+    }
+    #[cfg(windows)]
+    {
+        if tokio::signal::ctrl_c().await.is_err() {
+            warn!("Could not listen for shutdown signals");
+            return std::future::pending().await;
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// * `shutdown`: Triggered when a shutdown signal is received
+/// * `consumer`: Consumer loop, which is expected to stop taking messages and drain `shutdown`
+/// once it is triggered
+///
+/// Runs the consumer until it returns, triggering `shutdown` on SIGTERM or SIGINT
+///
+/// returns: ()
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+pub async fn run_until_signal<F: Future<Output = ()>>(shutdown: Arc<Shutdown>, consumer: F) {
+    let signal_shutdown = Arc::clone(&shutdown);
+    let signal_listener = tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown signal received, no longer consuming new messages");
+        signal_shutdown.trigger();
+    });
+    consumer.await;
+    signal_listener.abort();
+}
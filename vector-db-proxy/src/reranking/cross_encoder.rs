@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::reranking::traits::Reranker;
+
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+    score: f32,
+}
+
+/// Reranks with a cross-encoder served over HTTP using the `/rerank` API of Hugging Face's text
+/// embeddings inference server
+pub struct HttpCrossEncoder {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl HttpCrossEncoder {
+    pub fn new(url: String, api_key: Option<String>) -> Self {
+        HttpCrossEncoder {
+            client: Client::new(),
+            url,
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl Reranker for HttpCrossEncoder {
+    async fn rerank(&self, query: &str, documents: &[String]) -> Result<Vec<f32>> {
+        if documents.is_empty() {
+            return Ok(vec![]);
+        }
+        let body = json!({
+            "query": query,
+            "texts": documents,
+        });
+        let mut request = self.client.post(&self.url).json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let res = request.send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("Reranking request failed. Status: {}", res.status()));
+        }
+        let results: Vec<RerankResult> = res.json().await?;
+        // Results come back sorted by score, put them back in document order
+        let mut scores = vec![f32::MIN; documents.len()];
+        for result in results {
+            match scores.get_mut(result.index) {
+                Some(score) => *score = result.score,
+                None => {
+                    return Err(anyhow!(
+                        "Reranker returned a score for document {} but only {} were sent",
+                        result.index,
+                        documents.len()
+                    ))
+                }
+            }
+        }
+        Ok(scores)
+    }
+}
@@ -0,0 +1,2 @@
+pub mod cross_encoder;
+pub mod traits;
@@ -0,0 +1,11 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// Re-scores search candidates against the query, e.g. with a cross-encoder, which ranks much
+/// better than vector similarity alone but is too slow to run over a whole collection
+#[async_trait]
+pub trait Reranker: Send + Sync {
+    /// Returns one relevance score per document, in the same order as `documents`. Higher scores
+    /// are more relevant
+    async fn rerank(&self, query: &str, documents: &[String]) -> Result<Vec<f32>>;
+}
@@ -4,14 +4,19 @@ use actix_web::web::Data;
 use actix_web::*;
 use actix_web_lab::extract::Path;
 use std::sync::Arc;
+use tokio::sync::RwLock;
 
 use crate::errors::types::Result;
-use crate::qdrant::helpers::{get_next_page, get_scroll_results};
+use crate::metrics::utils::gather_metrics;
+use crate::qdrant::helpers::{
+    get_next_page, get_scroll_results, lookup_datasource_tenant, tenant_collection_name,
+};
 use crate::qdrant::models::{MyPoint, PointSearchResults, ScrollResults};
 use crate::qdrant::utils::Qdrant;
 use crate::routes;
 use crate::utils::conversions::convert_hashmap_to_filters;
 
+use mongodb::Database;
 use qdrant_client::client::QdrantClient;
 use qdrant_client::prelude::*;
 use qdrant_client::qdrant::vectors_config::Config;
@@ -25,10 +30,9 @@ use crate::mongo::models::Model;
 use crate::mongo::queries::get_embedding_model;
 use qdrant_client::qdrant::point_id::PointIdOptions;
 use qdrant_client::qdrant::with_vectors_selector::SelectorOptions;
-use routes::models::{ResponseBody, SearchRequest, Status};
+use routes::models::{CountRequest, ResponseBody, SearchRequest, Status};
 use serde_json::json;
 use std::vec;
-use tokio::sync::RwLock;
 use wherr::wherr;
 
 ///
@@ -54,7 +58,64 @@ pub async fn health_check() -> Result<impl Responder> {
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// Readiness probe. Returns 200 when Qdrant is reachable and 503 otherwise
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/healthz")]
+pub async fn readiness_check(app_data: Data<Arc<QdrantClient>>) -> Result<impl Responder> {
+    let qdrant_conn = app_data.get_ref().clone();
+    let qdrant = Qdrant::new(qdrant_conn, String::from(""));
+    match qdrant.health_check().await {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => {
+            log::warn!("{}", e);
+            Ok(HttpResponse::ServiceUnavailable()
+                .content_type(ContentType::json())
+                .json(json!(ResponseBody {
+                    status: Status::Failure,
+                    data: None,
+                    error_message: Some(json!({
+                        "errorMessage": e.to_string()
+                    }))
+                })))
+        }
+    }
+}
+
+///
+///
+/// # Arguments
+///
+/// Ingestion metrics in the Prometheus text format
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/metrics")]
+pub async fn prometheus_metrics() -> Result<impl Responder> {
+    let body = gather_metrics()?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+///
+///
+/// # Arguments
+///
+/// * `app_data`: Data<Arc<QdrantClient>>
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
 ///
@@ -65,7 +126,7 @@ pub async fn health_check() -> Result<impl Responder> {
 /// ```
 #[wherr]
 #[get("/list-collections")]
-pub async fn list_collections(app_data: Data<Arc<RwLock<QdrantClient>>>) -> Result<impl Responder> {
+pub async fn list_collections(app_data: Data<Arc<QdrantClient>>) -> Result<impl Responder> {
     let qdrant_conn = app_data.get_ref().clone();
     let qdrant = Qdrant::new(qdrant_conn, String::from(""));
     let results = qdrant.get_list_of_collections().await?;
@@ -82,8 +143,9 @@ pub async fn list_collections(app_data: Data<Arc<RwLock<QdrantClient>>>) -> Resu
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `app_data`: Data<Arc<QdrantClient>>
 /// * `Path(params)`:
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
 ///
 /// returns: Result<HttpResponse<BoxBody>, MyError>
 ///
@@ -95,12 +157,15 @@ pub async fn list_collections(app_data: Data<Arc<RwLock<QdrantClient>>>) -> Resu
 #[wherr]
 #[post("/create-collection/{collection_name}/{size}")]
 pub async fn create_collection(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(params): Path<(String, u64)>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<HttpResponse> {
-    let (collection_name, size) = params;
+    let (datasource_id, size) = params;
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &datasource_id).await?;
+    let collection_name = tenant_collection_name(tenant_id.as_deref(), &datasource_id);
     let qdrant_conn = app_data.get_ref().clone();
-    let qdrant_client = qdrant_conn.read().await;
+    let qdrant_client = qdrant_conn;
     let collection_creation_result = qdrant_client
         .create_collection(&CreateCollection {
             collection_name: collection_name.into(),
@@ -131,9 +196,10 @@ pub async fn create_collection(
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `app_data`: Data<Arc<QdrantClient>>
 /// * `Path(collection_name)`:
 /// * `data`:
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
 ///
@@ -145,9 +211,10 @@ pub async fn create_collection(
 #[wherr]
 #[post("/upsert-data-point/{collection_name}")]
 pub async fn upsert_data_point_to_collection(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(collection_name): Path<String>,
     data: web::Json<MyPoint>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<impl Responder> {
     let qdrant_conn = app_data.get_ref().clone();
 
@@ -156,7 +223,8 @@ pub async fn upsert_data_point_to_collection(
         data.vector.to_owned(),
         json!(data.payload).try_into().unwrap(),
     );
-    let qdrant = Qdrant::new(qdrant_conn, collection_name);
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &collection_name).await?;
+    let qdrant = Qdrant::new(qdrant_conn, collection_name).with_tenant(tenant_id);
     let upsert_results = qdrant.upsert_data_point_non_blocking(points).await?;
     println!("{:?}", upsert_results);
     match upsert_results {
@@ -183,9 +251,10 @@ pub async fn upsert_data_point_to_collection(
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `app_data`: Data<Arc<QdrantClient>>
 /// * `Path(collection_name)`:
 /// * `data`:
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
 ///
@@ -197,9 +266,10 @@ pub async fn upsert_data_point_to_collection(
 #[wherr]
 #[post("/bulk-upsert-data/{collection_name}")]
 pub async fn bulk_upsert_data_to_collection(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(collection_name): Path<String>,
     data: web::Json<Vec<MyPoint>>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<impl Responder> {
     let qdrant_conn = app_data.get_ref().clone();
     let mut list_of_points: Vec<PointStruct> = vec![];
@@ -212,7 +282,8 @@ pub async fn bulk_upsert_data_to_collection(
         list_of_points.push(point);
     }
     let collection_name_clone = collection_name.clone();
-    let qdrant = Qdrant::new(qdrant_conn, collection_name_clone);
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &collection_name).await?;
+    let qdrant = Qdrant::new(qdrant_conn, collection_name_clone).with_tenant(tenant_id);
     let mongodb_connection = start_mongo_connection().await.unwrap();
     let collection_name_clone_2 = collection_name.clone();
     let model_parameters: Model =
@@ -225,7 +296,7 @@ pub async fn bulk_upsert_data_to_collection(
         .bulk_upsert_data(list_of_points, Some(vector_length), None)
         .await?;
     println!("{:?}", bulk_upsert_results.to_owned());
-    match bulk_upsert_results {
+    match bulk_upsert_results.failed.is_empty() {
         true => Ok(HttpResponse::Ok()
             .content_type(ContentType::json())
             .json(json!(ResponseBody {
@@ -249,9 +320,10 @@ pub async fn bulk_upsert_data_to_collection(
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `app_data`: Data<Arc<QdrantClient>>
 /// * `Path(collection_name)`:
 /// * `data`:
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, MyError>
 ///
@@ -263,12 +335,15 @@ pub async fn bulk_upsert_data_to_collection(
 #[wherr]
 #[get("/lookup-data-point/{collection_name}")]
 pub async fn lookup_data_point(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(collection_name): Path<String>,
     data: web::Json<SearchRequest>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<impl Responder> {
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &collection_name).await?;
+    let collection_name = tenant_collection_name(tenant_id.as_deref(), &collection_name);
     let qdrant_conn = app_data.get_ref().clone();
-    let qdrant_conn_lock = qdrant_conn.read().await;
+    let qdrant_conn_lock = qdrant_conn;
     let vector = data.clone().vector.unwrap_or(vec![]).to_vec();
     let (must, must_not, should) = convert_hashmap_to_filters(&data.filters);
     let limit = data.limit.unwrap_or(3) as u64;
@@ -307,9 +382,10 @@ pub async fn lookup_data_point(
 ///
 /// # Arguments
 ///
-/// * `app_data`: Data<Arc<RwLock<QdrantClient>>>
+/// * `app_data`: Data<Arc<QdrantClient>>
 /// * `Path(dataset_id)`:
 /// * `data`: Query string parameters based on the `SearchRequest` struct
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
 ///
 /// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
 ///
@@ -321,18 +397,19 @@ pub async fn lookup_data_point(
 #[wherr]
 #[get("/scroll/{dataset_id}")]
 pub async fn scroll_data(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(dataset_id): Path<String>,
     data: web::Query<SearchRequest>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<impl Responder> {
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &dataset_id).await?;
+    let dataset_id = tenant_collection_name(tenant_id.as_deref(), &dataset_id);
     let qdrant_conn = app_data.get_ref();
     // Initialise lists
     let mut response: Vec<ScrollResults> = vec![];
     // Create a hash map of all filters provided by the client
     let (must, must_not, should) = convert_hashmap_to_filters(&data.filters);
     if qdrant_conn
-        .read()
-        .await
         .collection_exists(dataset_id.clone())
         .await?
         == false
@@ -382,7 +459,7 @@ pub async fn scroll_data(
                 });
             }
         } else {
-            let result = qdrant_conn.read().await.scroll(&scroll_points).await?;
+            let result = qdrant_conn.scroll(&scroll_points).await?;
             response.extend(get_scroll_results(result)?);
         }
     }
@@ -396,15 +473,55 @@ pub async fn scroll_data(
         })))
 }
 
+///
+///
+/// # Arguments
+///
+/// * `app_data`: Data<Arc<QdrantClient>>
+/// * `Path(dataset_id)`: The datasource to count points for
+/// * `data`: Query string parameters based on the `CountRequest` struct. Counts are exact
+/// unless `exact=false` is passed
+/// * `mongo_data`: Used to look up the tenant the datasource's collection is namespaced by
+///
+/// returns: Result<impl Responder<Body=<unknown>>+Sized, CustomErrorType>
+///
+/// # Examples
+///
+/// ```
+///
+/// ```
+#[wherr]
+#[get("/count/{dataset_id}")]
+pub async fn count_points(
+    app_data: Data<Arc<QdrantClient>>,
+    Path(dataset_id): Path<String>,
+    data: web::Query<CountRequest>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
+) -> Result<impl Responder> {
+    let qdrant_conn = app_data.get_ref();
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &dataset_id).await?;
+    let qdrant = Qdrant::new(Arc::clone(qdrant_conn), dataset_id).with_tenant(tenant_id);
+    let count = qdrant.count(data.exact.unwrap_or(true)).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!(ResponseBody {
+            status: Status::Success,
+            data: Some(json!({"count": count})),
+            error_message: None
+        })))
+}
+
 #[wherr]
 #[delete("/collection/{dataset_id}")]
 pub async fn delete_collection(
-    app_data: Data<Arc<RwLock<QdrantClient>>>,
+    app_data: Data<Arc<QdrantClient>>,
     Path(dataset_id): Path<String>,
+    mongo_data: Data<Arc<RwLock<Database>>>,
 ) -> Result<impl Responder> {
     let dataset_id_clone = dataset_id.clone();
     let qdrant_conn = app_data.get_ref();
-    let qdrant = Qdrant::new(Arc::clone(qdrant_conn), dataset_id_clone);
+    let tenant_id = lookup_datasource_tenant(mongo_data.get_ref(), &dataset_id).await?;
+    let qdrant = Qdrant::new(Arc::clone(qdrant_conn), dataset_id_clone).with_tenant(tenant_id);
     match qdrant.delete_collection().await {
         Ok(()) => Ok(HttpResponse::Ok()
             .content_type(ContentType::json())
@@ -34,6 +34,11 @@ pub struct SearchRequest{
     pub get_all_pages: Option<bool>
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CountRequest{
+    pub exact: Option<bool>
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Prompt{
     pub prompt: Vec<String>,
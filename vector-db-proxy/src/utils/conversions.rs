@@ -1,5 +1,7 @@
+use crate::qdrant::models::HashMapValues;
 use crate::routes::models::FilterConditions;
-use qdrant_client::qdrant::Condition;
+use anyhow::{anyhow, Result};
+use qdrant_client::qdrant::{Condition, Distance, FieldType, Range};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 
@@ -13,6 +15,37 @@ pub fn convert_serde_value_to_hashmap_string(
     return hashmap_serde;
 }
 
+/// Converts a JSON object into a map of `HashMapValues`. Nested objects are flattened into
+/// dot-notation keys (e.g. `address.city`) and arrays of scalars are kept as lists so that
+/// Qdrant can filter on them.
+pub fn convert_serde_value_to_hashmap_value(
+    serde_value: Map<String, Value>,
+) -> HashMap<String, HashMapValues> {
+    let mut hashmap_values: HashMap<String, HashMapValues> = HashMap::new();
+    for (k, v) in serde_value {
+        flatten_serde_value(k, v, &mut hashmap_values);
+    }
+    hashmap_values
+}
+
+fn flatten_serde_value(key: String, value: Value, target: &mut HashMap<String, HashMapValues>) {
+    match value {
+        Value::Object(obj) if !obj.is_empty() => {
+            for (k, v) in obj {
+                flatten_serde_value(format!("{}.{}", key, k), v, target);
+            }
+        }
+        Value::Array(arr) if arr.iter().all(|v| !v.is_object() && !v.is_array()) => {
+            let list = arr.into_iter().map(HashMapValues::from).collect();
+            target.insert(key, HashMapValues::List(list));
+        }
+        // Empty objects and arrays containing objects or arrays are stored as they are
+        other => {
+            target.insert(key, HashMapValues::from(other));
+        }
+    }
+}
+
 pub fn convert_hashmap_to_filters(
     filters: &Option<FilterConditions>,
 ) -> (Vec<Condition>, Vec<Condition>, Vec<Condition>) {
@@ -36,3 +69,181 @@ pub fn convert_hashmap_to_filters(
 
     (must_vec, must_not_vec, should_vec)
 }
+
+/// Translates payload key/value pairs into equality conditions. Strings, integers and booleans
+/// are matched exactly, floats are matched with a range that starts and ends at the value.
+pub fn convert_hashmap_values_to_conditions(
+    filter: &HashMap<String, HashMapValues>,
+) -> Result<Vec<Condition>> {
+    let mut conditions: Vec<Condition> = vec![];
+    for (k, v) in filter {
+        let condition = match v {
+            HashMapValues::Str(s) => Condition::matches(k.to_string(), s.to_string()),
+            HashMapValues::Serde(Value::String(s)) => Condition::matches(k.to_string(), s.to_string()),
+            HashMapValues::Serde(Value::Bool(b)) => Condition::matches(k.to_string(), *b),
+            HashMapValues::Int(i) => Condition::matches(k.to_string(), *i),
+            HashMapValues::Float(f) => Condition::range(
+                k.to_string(),
+                Range {
+                    gte: Some(*f),
+                    lte: Some(*f),
+                    ..Default::default()
+                },
+            ),
+            HashMapValues::Serde(Value::Number(n)) => match n.as_i64() {
+                Some(i) => Condition::matches(k.to_string(), i),
+                None => {
+                    let f = n.as_f64().unwrap_or_default();
+                    Condition::range(
+                        k.to_string(),
+                        Range {
+                            gte: Some(f),
+                            lte: Some(f),
+                            ..Default::default()
+                        },
+                    )
+                }
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Filtering on field '{}' is not supported for value: {}",
+                    k,
+                    v
+                ))
+            }
+        };
+        conditions.push(condition);
+    }
+    Ok(conditions)
+}
+
+/// Maps a distance metric name (e.g. from an env variable) to a Qdrant `Distance`.
+/// Unknown names fall back to Cosine
+pub fn convert_string_to_distance(distance: &str) -> Distance {
+    match distance.to_lowercase().as_str() {
+        "dot" => Distance::Dot,
+        "euclid" | "euclidean" => Distance::Euclid,
+        "manhattan" => Distance::Manhattan,
+        _ => Distance::Cosine,
+    }
+}
+
+/// Parses a comma separated list of `field:type` pairs (e.g. `chunk_index:integer,status:keyword`)
+/// into payload fields to index. Fields without a type, or with an unknown one, are indexed as
+/// keywords
+pub fn convert_string_to_payload_indexes(payload_indexes: &str) -> Vec<(String, FieldType)> {
+    payload_indexes
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((field, field_type)) => {
+                let field_type = match field_type.trim().to_lowercase().as_str() {
+                    "integer" => FieldType::Integer,
+                    "float" => FieldType::Float,
+                    "bool" => FieldType::Bool,
+                    "text" => FieldType::Text,
+                    "datetime" => FieldType::Datetime,
+                    "geo" => FieldType::Geo,
+                    _ => FieldType::Keyword,
+                };
+                (field.trim().to_string(), field_type)
+            }
+            None => (entry.to_string(), FieldType::Keyword),
+        })
+        .collect()
+}
+
+/// Parses a comma separated list of `datasource_id:model` pairs (e.g.
+/// `65f1c2ab:text-embedding-3-small`) into the embedding model to use for each datasource.
+/// Entries without a model are ignored
+pub fn convert_string_to_model_overrides(model_overrides: &str) -> HashMap<String, String> {
+    model_overrides
+        .split(',')
+        .filter_map(|entry| entry.split_once(':'))
+        .map(|(datasource_id, model)| (datasource_id.trim().to_string(), model.trim().to_string()))
+        .filter(|(datasource_id, model)| !datasource_id.is_empty() && !model.is_empty())
+        .collect()
+}
+
+/// Parses a CSV message into records keyed by the header row. Cells that parse as integers or
+/// floats become JSON numbers and everything else is kept as a string, so records end up the
+/// same as their JSON equivalent
+pub fn convert_csv_to_records(message: &str) -> Result<Vec<Map<String, Value>>, csv::Error> {
+    let mut reader = csv::Reader::from_reader(message.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut records = vec![];
+    for row in reader.records() {
+        let row = row?;
+        let record: Map<String, Value> = headers
+            .iter()
+            .zip(row.iter())
+            .map(|(header, cell)| (header.to_string(), convert_csv_cell_to_value(cell)))
+            .collect();
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn convert_csv_cell_to_value(cell: &str) -> Value {
+    if let Ok(i) = cell.parse::<i64>() {
+        return Value::from(i);
+    }
+    match cell.parse::<f64>() {
+        // `inf` and `NaN` parse as floats but have no JSON representation
+        Ok(f) if f.is_finite() => Value::from(f),
+        _ => Value::String(cell.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_record(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(record) => record,
+            _ => panic!("not a JSON object"),
+        }
+    }
+
+    #[test]
+    fn flattens_two_level_nested_objects_into_dot_notation_keys() {
+        let record = to_record(json!({
+            "name": "Acme",
+            "address": {"city": "Sydney", "geo": {"lat": -33.86, "postcode": 2000}}
+        }));
+        let values = convert_serde_value_to_hashmap_value(record);
+        assert_eq!(values.len(), 4);
+        assert!(matches!(&values["name"], HashMapValues::Str(s) if s == "Acme"));
+        assert!(matches!(&values["address.city"], HashMapValues::Str(s) if s == "Sydney"));
+        assert!(matches!(values["address.geo.lat"], HashMapValues::Float(f) if f == -33.86));
+        assert!(matches!(values["address.geo.postcode"], HashMapValues::Int(2000)));
+        assert!(!values.contains_key("address"));
+    }
+
+    #[test]
+    fn keeps_mixed_arrays_of_scalars_as_lists() {
+        let record = to_record(json!({"tags": ["faq", 3, 1.5, true]}));
+        let values = convert_serde_value_to_hashmap_value(record);
+        let HashMapValues::List(list) = &values["tags"] else {
+            panic!("tags should be a list");
+        };
+        assert_eq!(list.len(), 4);
+        assert!(matches!(&list[0], HashMapValues::Str(s) if s == "faq"));
+        assert!(matches!(list[1], HashMapValues::Int(3)));
+        assert!(matches!(list[2], HashMapValues::Float(f) if f == 1.5));
+        assert!(matches!(&list[3], HashMapValues::Serde(Value::Bool(true))));
+    }
+
+    #[test]
+    fn stores_arrays_of_objects_as_they_are() {
+        let record = to_record(json!({"contacts": [{"name": "a"}, {"name": "b"}]}));
+        let values = convert_serde_value_to_hashmap_value(record);
+        assert!(matches!(
+            &values["contacts"],
+            HashMapValues::Serde(Value::Array(contacts)) if contacts.len() == 2
+        ));
+    }
+}
@@ -16,4 +16,41 @@ pub fn negative_vector(vector: &mut Vec<f32>) -> Option<Vec<f32>>{
 
     }
     return None;
-}
\ No newline at end of file
+}
+
+/// Scales the vector to unit length in place. A zero vector is left as is since it has no
+/// direction to preserve
+pub fn l2_normalize(vector: &mut [f32]) {
+    let magnitude = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if magnitude > f32::EPSILON {
+        for num in vector.iter_mut() {
+            *num /= magnitude;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn magnitude(vector: &[f32]) -> f32 {
+        vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+    }
+
+    #[test]
+    fn normalized_vectors_have_unit_magnitude() {
+        let mut vector = vec![3.0, 4.0, 12.0];
+        l2_normalize(&mut vector);
+        assert!((magnitude(&vector) - 1.0).abs() < 1e-6);
+        // The direction is kept
+        assert!((vector[0] - 3.0 / 13.0).abs() < 1e-6);
+        assert!((vector[2] - 12.0 / 13.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zero_vectors_are_left_as_is() {
+        let mut vector = vec![0.0; 4];
+        l2_normalize(&mut vector);
+        assert_eq!(vector, vec![0.0; 4]);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod traits;
+#[cfg(test)]
+pub mod test_utils;
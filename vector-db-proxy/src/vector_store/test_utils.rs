@@ -0,0 +1,82 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use qdrant_client::qdrant::{PointId, PointStruct};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::qdrant::helpers::point_id_to_string;
+use crate::qdrant::models::UpsertReport;
+use crate::vector_store::traits::VectorStore;
+
+/// Keeps a datasource's points in memory, in the order they were first upserted. Like Qdrant, an
+/// upsert overwrites the point with the same id
+#[derive(Default)]
+pub struct InMemoryVectorStore {
+    pub datasource_id: String,
+    pub points: Mutex<Vec<PointStruct>>,
+}
+
+impl InMemoryVectorStore {
+    /// Ids of the stored points, sorted
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .points
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|point| point_id_to_string(&point.id))
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+#[async_trait]
+impl VectorStore for InMemoryVectorStore {
+    fn datasource_id(&self) -> &str {
+        &self.datasource_id
+    }
+
+    async fn ensure_collection(&self, _: u64, _: Option<String>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn bulk_upsert(
+        &self,
+        points: Vec<PointStruct>,
+        _: Option<u64>,
+        _: Option<String>,
+    ) -> Result<UpsertReport> {
+        let succeeded = points.len();
+        let mut stored = self.points.lock().unwrap();
+        for point in points {
+            match stored.iter_mut().find(|stored| stored.id == point.id) {
+                Some(existing) => *existing = point,
+                None => stored.push(point),
+            }
+        }
+        Ok(UpsertReport { succeeded, failed: vec![] })
+    }
+
+    async fn delete_by_datasource(&self) -> Result<u64> {
+        let mut points = self.points.lock().unwrap();
+        let deleted = points.len() as u64;
+        points.clear();
+        Ok(deleted)
+    }
+
+    async fn point_ids(&self) -> Result<Vec<PointId>> {
+        let points = self.points.lock().unwrap();
+        Ok(points.iter().filter_map(|point| point.id.clone()).collect())
+    }
+
+    async fn delete_points(&self, ids: Vec<PointId>) -> Result<()> {
+        let ids: HashSet<String> =
+            ids.into_iter().map(|id| point_id_to_string(&Some(id))).collect();
+        self.points
+            .lock()
+            .unwrap()
+            .retain(|point| !ids.contains(&point_id_to_string(&point.id)));
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use qdrant_client::qdrant::{PointId, PointStruct};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+use crate::qdrant::helpers::point_id_to_string;
+use crate::qdrant::models::{ReplaceReport, UpsertReport};
+
+/// The operations the ingestion pipeline needs from a vector database. Each instance is scoped to
+/// a single datasource. Points are exchanged as Qdrant `PointStruct`s (id, named vectors and a
+/// JSON payload), other backends are expected to convert them to their own representation.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// The datasource this instance is scoped to
+    fn datasource_id(&self) -> &str;
+
+    /// Creates the datasource's collection if it does not exist yet
+    async fn ensure_collection(&self, vector_size: u64, vector_name: Option<String>) -> Result<()>;
+
+    /// Writes points to the datasource's collection, creating it first if needed
+    async fn bulk_upsert(
+        &self,
+        points: Vec<PointStruct>,
+        vector_length: Option<u64>,
+        vector_name: Option<String>,
+    ) -> Result<UpsertReport>;
+
+    /// Removes every point belonging to the datasource. Returns the number of points removed
+    async fn delete_by_datasource(&self) -> Result<u64>;
+
+    /// Ids of every point belonging to the datasource
+    async fn point_ids(&self) -> Result<Vec<PointId>>;
+
+    /// Removes the datasource's points with these ids
+    async fn delete_points(&self, ids: Vec<PointId>) -> Result<()>;
+
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `points`: Every point of a full sync of the datasource. Point ids must be deterministic
+    /// (see `generate_point_id`) so that unchanged records overwrite their previous point
+    /// * `vector_length`: Passed on to `bulk_upsert`
+    /// * `vector_name`: Passed on to `bulk_upsert`
+    ///
+    /// Makes the new points the datasource's complete set: they are upserted first and only then
+    /// are the datasource's points whose ids are not among them deleted, so readers never see an
+    /// empty datasource. Between the two steps they do see old and new points mixed: new and
+    /// changed records are already written while records dropped by the sync are still there. If
+    /// any point fails to upsert nothing is deleted, so that the previous sync's points remain
+    /// available
+    ///
+    /// returns: Result<ReplaceReport, Error>
+    async fn replace_datasource(
+        &self,
+        points: Vec<PointStruct>,
+        vector_length: Option<u64>,
+        vector_name: Option<String>,
+    ) -> Result<ReplaceReport> {
+        let new_ids: HashSet<String> = points
+            .iter()
+            .map(|point| point_id_to_string(&point.id))
+            .collect();
+        let upserted = self.bulk_upsert(points, vector_length, vector_name).await?;
+        if !upserted.failed.is_empty() {
+            warn!(
+                datasource_id = self.datasource_id(),
+                failed = upserted.failed.len(),
+                "Points failed to upsert, keeping the previous points"
+            );
+            return Ok(ReplaceReport {
+                upserted,
+                deleted: 0,
+            });
+        }
+        let stale_ids: Vec<PointId> = self
+            .point_ids()
+            .await?
+            .into_iter()
+            .filter(|id| !new_ids.contains(&point_id_to_string(&Some(id.clone()))))
+            .collect();
+        let deleted = stale_ids.len() as u64;
+        self.delete_points(stale_ids).await?;
+        info!(
+            datasource_id = self.datasource_id(),
+            deleted,
+            "Replaced points of datasource"
+        );
+        Ok(ReplaceReport { upserted, deleted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_store::test_utils::InMemoryVectorStore;
+    use qdrant_client::prelude::Payload;
+
+    fn sync(rows: &[u64]) -> Vec<PointStruct> {
+        rows.iter()
+            .map(|row| PointStruct::new(*row, vec![*row as f32, 1.0], Payload::new()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_new_sync_replaces_the_previous_one() {
+        let store = InMemoryVectorStore::default();
+        store.replace_datasource(sync(&[1, 2, 3]), None, None).await.unwrap();
+        // Row 2 was dropped from the source and row 4 added
+        let report = store.replace_datasource(sync(&[1, 3, 4]), None, None).await.unwrap();
+        assert_eq!(report.upserted.succeeded, 3);
+        assert_eq!(report.deleted, 1);
+        assert_eq!(store.ids(), vec!["1", "3", "4"]);
+    }
+}